@@ -1,20 +1,28 @@
-use std::fmt::{Arguments, Display};
+use std::fmt::Display;
 
 use crate::token::{Literal, Token, TokenType};
 
 type WrappedExpr = Box<Expr>;
 
+/// The number of environments between a variable's use and its declaration, as determined by
+/// the resolver. `None` means the variable is global.
+pub(crate) type Depth = Option<usize>;
+
 #[derive(Debug, Clone)]
 pub(crate) enum Expr {
     Literal {
         value: Literal,
+        /// The token the literal was written as, for locating it in the source.
+        token: Token,
     },
     Variable {
         name: Token,
+        depth: Depth,
     },
     Assign {
         name: Token,
         value: WrappedExpr,
+        depth: Depth,
     },
     Logical {
         left: WrappedExpr,
@@ -35,17 +43,64 @@ pub(crate) enum Expr {
         paren: Token,
         arguments: Vec<Expr>,
     },
+    /// An anonymous function.
+    Function {
+        keyword: Token,
+        params: Vec<Token>,
+        body: Vec<Stmt>,
+    },
     Grouping {
         expression: WrappedExpr,
     },
+    List {
+        /// The opening bracket.
+        bracket: Token,
+        elements: Vec<Expr>,
+    },
+    Map {
+        /// The opening brace.
+        brace: Token,
+        /// The key and value expressions of each entry.
+        entries: Vec<(Expr, Expr)>,
+    },
+    Index {
+        object: WrappedExpr,
+        /// The opening bracket, for locating errors.
+        bracket: Token,
+        index: WrappedExpr,
+    },
+    SetIndex {
+        object: WrappedExpr,
+        bracket: Token,
+        index: WrappedExpr,
+        value: WrappedExpr,
+    },
+    Get {
+        object: WrappedExpr,
+        name: Token,
+    },
+    Set {
+        object: WrappedExpr,
+        name: Token,
+        value: WrappedExpr,
+    },
+    Super {
+        keyword: Token,
+        method: Token,
+        depth: Depth,
+    },
+    This {
+        keyword: Token,
+        depth: Depth,
+    },
 }
 
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expr::Literal { value } => write!(f, "{value}"),
-            Expr::Variable { name } => write!(f, "{name}"),
-            Expr::Assign { name, value } => write!(f, "{name} = {value}"),
+            Expr::Literal { value, .. } => write!(f, "{value}"),
+            Expr::Variable { name, .. } => write!(f, "{name}"),
+            Expr::Assign { name, value, .. } => write!(f, "{name} = {value}"),
             Expr::Logical {
                 left,
                 operator,
@@ -73,7 +128,34 @@ impl Display for Expr {
                 arguments.truncate(arguments.len() - 2);
                 write!(f, "{callee}({arguments})")
             }
+            Expr::Function { .. } => write!(f, "<fn>"),
             Expr::Grouping { expression } => write!(f, "{expression}"),
+            Expr::List { elements, .. } => {
+                let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Expr::Map { entries, .. } => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{key}: {value}"))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Expr::Index { object, index, .. } => write!(f, "{object}[{index}]"),
+            Expr::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => write!(f, "{object}[{index}] = {value}"),
+            Expr::Get { object, name } => write!(f, "{object}.{}", name.lexeme()),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "{object}.{} = {value}", name.lexeme()),
+            Expr::Super { method, .. } => write!(f, "super.{}", method.lexeme()),
+            Expr::This { .. } => write!(f, "this"),
         }
     }
 }
@@ -85,6 +167,17 @@ pub(crate) enum Stmt {
     Block {
         statements: Vec<Stmt>,
     },
+    Break {
+        keyword: Token,
+    },
+    Class {
+        name: Token,
+        superclass: Option<Expr>,
+        methods: Vec<Stmt>,
+    },
+    Continue {
+        keyword: Token,
+    },
     Expression {
         expression: Expr,
     },
@@ -112,6 +205,9 @@ pub(crate) enum Stmt {
     While {
         condition: Expr,
         body: WrappedStmt,
+        /// The increment clause of a desugared `for` loop. It runs after every iteration of the
+        /// body, including those cut short by `continue`.
+        increment: Option<Expr>,
     },
 }
 
@@ -127,7 +223,10 @@ impl Display for Stmt {
                     .collect::<Vec<_>>()
                     .join("  ")
             ),
-            Stmt::Function { name, params, body } => write!(f, "<fn {name}>", name = name.lexeme()),
+            Stmt::Break { keyword } => write!(f, "{}", keyword.lexeme()),
+            Stmt::Class { name, .. } => write!(f, "<class {name}>", name = name.lexeme()),
+            Stmt::Function { name, .. } => write!(f, "<fn {name}>", name = name.lexeme()),
+            Stmt::Continue { keyword } => write!(f, "{}", keyword.lexeme()),
             Stmt::Expression { expression } => write!(f, "{expression}"),
             Stmt::If {
                 condition,
@@ -156,7 +255,16 @@ impl Display for Stmt {
                 name,
                 initializer: None,
             } => write!(f, "var {name}"),
-            Stmt::While { condition, body } => write!(f, "while ({condition}) {body}"),
+            Stmt::While {
+                condition,
+                body,
+                increment: None,
+            } => write!(f, "while ({condition}) {body}"),
+            Stmt::While {
+                condition,
+                body,
+                increment: Some(increment),
+            } => write!(f, "while ({condition}; {increment}) {body}"),
         }
     }
 }
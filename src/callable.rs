@@ -1,17 +1,19 @@
-use crate::ast::{Expr, Stmt};
-use crate::environment::{self, Environment};
-use crate::interpreter::Interpreter;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use crate::ast::Stmt;
+use crate::environment::{Environment, EnvironmentRef};
+use crate::interpreter::{ControlFlow, Interpreter};
 use crate::token::{Literal, Token};
-use crate::LoxError;
+use crate::{Frame, LoxError, RuntimeError};
 
 pub(crate) trait Callable {
-    fn new(declaration: Stmt) -> Option<Self>
-    where
-        Self: Sized;
+    /// Call this callable with `arguments`. The `paren` token of the call site is used to locate
+    /// any errors raised by the callable itself.
     fn call(
         &self,
         interpreter: &mut Interpreter,
-        environment: &Environment,
+        paren: &Token,
         arguments: Vec<Literal>,
     ) -> Result<Literal, LoxError>;
     fn arity(&self) -> usize;
@@ -19,50 +21,200 @@ pub(crate) trait Callable {
 
 #[derive(Debug, Clone)]
 pub struct Function {
+    /// The name of the function, or the `fun` keyword of an anonymous function.
     name: Token,
+    anonymous: bool,
     params: Vec<String>,
-    body: Vec<Stmt>,
+    /// Shared by the copies of this function, which tells it apart from the functions made by
+    /// other evaluations of the same declaration.
+    body: Rc<[Stmt]>,
+    /// The environment this function was declared in.
+    closure: EnvironmentRef,
+    /// The instance `this` is bound to, for a method that was looked up on one.
+    receiver: Option<Literal>,
+    is_initializer: bool,
 }
 
 impl Function {
+    pub(crate) fn new(declaration: Stmt, closure: EnvironmentRef) -> Option<Self> {
+        match declaration {
+            Stmt::Function { name, params, body } => {
+                Some(Self::from_parts(name, false, &params, body, closure))
+            }
+            _ => None,
+        }
+    }
+
+    /// Create an anonymous function from the parts of an `Expr::Function`.
+    pub(crate) fn anonymous(
+        keyword: Token,
+        params: &[Token],
+        body: Vec<Stmt>,
+        closure: EnvironmentRef,
+    ) -> Self {
+        Self::from_parts(keyword, true, params, body, closure)
+    }
+
+    fn from_parts(
+        name: Token,
+        anonymous: bool,
+        params: &[Token],
+        body: Vec<Stmt>,
+        closure: EnvironmentRef,
+    ) -> Self {
+        let params = params
+            .iter()
+            .map(|param| param.lexeme().to_string())
+            .collect();
+        Self {
+            name,
+            anonymous,
+            params,
+            body: body.into(),
+            closure,
+            receiver: None,
+            is_initializer: false,
+        }
+    }
+
     pub(crate) fn name(&self) -> &Token {
         &self.name
     }
-}
 
-impl Callable for Function {
-    fn new(declaration: Stmt) -> Option<Self> {
-        match declaration {
-            Stmt::Function { name, params, body } => {
-                let params = params
-                    .iter()
-                    .map(|param| param.lexeme().to_string())
-                    .collect();
-                Some(Self { name, params, body })
+    pub(crate) fn is_anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    /// Whether this and `other` are the same function: made by the same evaluation of a
+    /// declaration, in the same environment, and bound to the same instance if they are methods.
+    pub(crate) fn is_same(&self, other: &Function) -> bool {
+        Rc::ptr_eq(&self.body, &other.body)
+            && match (&self.receiver, &other.receiver) {
+                (None, None) => Rc::ptr_eq(&self.closure, &other.closure),
+                (Some(a), Some(b)) => Literal::is_equal(a.clone(), b.clone()).is_truthy(),
+                _ => false,
             }
-            _ => None,
+    }
+
+    /// Mark this function as a class initializer, which always returns `this`.
+    pub(crate) fn into_initializer(self) -> Self {
+        Self {
+            is_initializer: true,
+            ..self
         }
     }
 
+    /// Create a copy of this method in which `this` is bound to `instance`.
+    pub(crate) fn bind(&self, instance: Literal) -> Self {
+        let mut environment = Environment::from_parent(&self.closure);
+        environment.define("this".to_string(), instance.clone());
+        Self {
+            closure: environment.shared(),
+            receiver: Some(instance),
+            ..self.clone()
+        }
+    }
+}
+
+impl Callable for Function {
     fn call(
         &self,
         interpreter: &mut Interpreter,
-        environment: &Environment,
+        paren: &Token,
         arguments: Vec<Literal>,
     ) -> Result<Literal, LoxError> {
-        let mut environment = Environment::from_parent(environment);
+        let mut environment = Environment::from_parent(&self.closure);
 
         for (n, param) in self.params.iter().enumerate() {
             // TODO: Is this unwrap guaranteed by invariants from parsing process?
             environment.define(param.to_string(), arguments.get(n).unwrap().clone());
         }
 
-        interpreter.execute_block(self.body.clone(), &mut environment)?;
+        let name = if self.anonymous {
+            ""
+        } else {
+            self.name.lexeme()
+        };
+        interpreter.push_frame(Frame::new(name, paren.line(), paren.col()))?;
+        let flow = interpreter.execute_block(self.body.to_vec(), environment.shared())?;
+        interpreter.pop_frame();
+
+        // An initializer hands back the instance, even after an early `return;`.
+        if self.is_initializer {
+            // The closure of a bound initializer is the environment holding `this`.
+            if let Some(this) = self.closure.borrow().get("this") {
+                return Ok(this);
+            }
+        }
 
-        Ok(Literal::Nil)
+        match flow {
+            ControlFlow::Return(value) => Ok(value),
+            _ => Ok(Literal::Nil),
+        }
     }
 
     fn arity(&self) -> usize {
         self.params.len()
     }
 }
+
+/// The signature of a function implemented in Rust.
+///
+/// An `Err` carries a runtime error, which is reported at the call site.
+pub(crate) type NativeFn = dyn Fn(Vec<Literal>) -> Result<Literal, RuntimeError>;
+
+/// A function implemented in Rust that can be called from Lox.
+#[derive(Clone)]
+pub struct NativeFunction {
+    name: String,
+    arity: usize,
+    function: Rc<NativeFn>,
+}
+
+impl NativeFunction {
+    pub(crate) fn new(
+        name: &str,
+        arity: usize,
+        function: impl Fn(Vec<Literal>) -> Result<Literal, RuntimeError> + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            arity,
+            function: Rc::new(function),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the host function on `arguments`, which must already match the arity.
+    pub(crate) fn invoke(&self, arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+        (self.function)(arguments)
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Callable for NativeFunction {
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Literal>,
+    ) -> Result<Literal, LoxError> {
+        self.invoke(arguments)
+            .map_err(|error| error.located(|message| LoxError::from_token(paren, message)))
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt::Display;
+use std::rc::Rc;
+
+/// The instruction set of the bytecode virtual machine.
+///
+/// Operands follow the opcode byte directly. Constant indices and jump offsets are two bytes
+/// wide, in big-endian order. Local slots, upvalue indices and argument counts are one byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum OpCode {
+    /// `Constant idx:u16` — push the constant at `idx`.
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    /// `GetLocal slot:u8`
+    GetLocal,
+    /// `SetLocal slot:u8`
+    SetLocal,
+    /// `GetGlobal name:u16`
+    GetGlobal,
+    /// `DefineGlobal name:u16`
+    DefineGlobal,
+    /// `SetGlobal name:u16`
+    SetGlobal,
+    /// `GetUpvalue index:u8`
+    GetUpvalue,
+    /// `SetUpvalue index:u8`
+    SetUpvalue,
+    /// `GetProperty name:u16`
+    GetProperty,
+    /// `SetProperty name:u16`
+    SetProperty,
+    /// `GetSuper name:u16`
+    GetSuper,
+    /// `BuildList count:u16` — replace the top `count` values with a list of them.
+    BuildList,
+    /// `BuildMap count:u16` — replace the top `count` pairs of keys and values with a map of them.
+    BuildMap,
+    GetIndex,
+    SetIndex,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// `Jump offset:u16` — jump forward.
+    Jump,
+    /// `JumpIfFalse offset:u16` — jump forward if the top of the stack is falsey, without
+    /// popping it.
+    JumpIfFalse,
+    /// `Loop offset:u16` — jump backward.
+    Loop,
+    /// `Call argc:u8`
+    Call,
+    /// `Closure function:u16` followed by an `is_local:u8, index:u8` pair for every upvalue of
+    /// the function.
+    Closure,
+    CloseUpvalue,
+    Return,
+    /// `Class name:u16`
+    Class,
+    /// `Inherit superclass:u16` — the operand names the superclass variable, for errors.
+    Inherit,
+    /// `Method name:u16`
+    Method,
+}
+
+impl OpCode {
+    /// All opcodes, indexed by their byte value.
+    const ALL: [OpCode; 41] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::BuildList,
+        OpCode::BuildMap,
+        OpCode::GetIndex,
+        OpCode::SetIndex,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+    ];
+
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
+
+/// A value in the constant pool of a chunk.
+#[derive(Debug, Clone)]
+pub(crate) enum Constant {
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Prototype>),
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constant::Number(n) => write!(f, "{n}"),
+            Constant::String(s) => write!(f, "{s:?}"),
+            Constant::Function(function) => write!(f, "{function}"),
+        }
+    }
+}
+
+/// Marks the source position of the instructions starting at `offset`, up to the next entry in
+/// the line table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LineStart {
+    pub(crate) offset: usize,
+    pub(crate) line: usize,
+    pub(crate) col: usize,
+}
+
+/// A sequence of bytecode, together with the constants it refers to and the source positions
+/// it was compiled from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Chunk {
+    code: Vec<u8>,
+    constants: Vec<Constant>,
+    lines: Vec<LineStart>,
+}
+
+impl Chunk {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reassemble a chunk from its parts, as taken apart by the accessors below.
+    pub(crate) fn from_parts(
+        code: Vec<u8>,
+        constants: Vec<Constant>,
+        lines: Vec<LineStart>,
+    ) -> Self {
+        Self {
+            code,
+            constants,
+            lines,
+        }
+    }
+
+    pub(crate) fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub(crate) fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    pub(crate) fn lines(&self) -> &[LineStart] {
+        &self.lines
+    }
+
+    /// Append a byte that was compiled from the token at `line` and `col`.
+    pub(crate) fn write(&mut self, byte: u8, line: usize, col: usize) {
+        let same_position = matches!(
+            self.lines.last(),
+            Some(last) if last.line == line && last.col == col
+        );
+        if !same_position {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line,
+                col,
+            });
+        }
+        self.code.push(byte);
+    }
+
+    /// Overwrite the byte at `offset`, which must already have been written.
+    pub(crate) fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    /// Add a constant to the pool and return its index.
+    pub(crate) fn add_constant(&mut self, constant: Constant) -> usize {
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+
+    /// The `(line, col)` of the source that the byte at `offset` was compiled from.
+    pub(crate) fn position(&self, offset: usize) -> (usize, usize) {
+        let index = self.lines.partition_point(|start| start.offset <= offset);
+        match index.checked_sub(1).and_then(|i| self.lines.get(i)) {
+            Some(start) => (start.line, start.col),
+            None => (0, 0),
+        }
+    }
+}
+
+/// A compiled function: its bytecode and everything needed to create closures from it.
+#[derive(Debug, Clone)]
+pub(crate) struct Prototype {
+    /// The name of the function, or the empty string for an anonymous function and for the
+    /// top-level script.
+    pub(crate) name: String,
+    pub(crate) arity: usize,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Chunk,
+}
+
+impl Display for Prototype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "<fn>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::Stmt;
+use crate::callable::{Callable, Function};
+use crate::diagnostic::Code;
+use crate::environment::EnvironmentRef;
+use crate::interpreter::Interpreter;
+use crate::token::{Literal, Token};
+use crate::LoxError;
+
+#[derive(Debug)]
+pub struct Class {
+    name: Token,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<String, Function>,
+}
+
+impl Class {
+    /// Create a class from a `Stmt::Class` declaration.
+    ///
+    /// The superclass expression of the declaration must already be evaluated by the caller,
+    /// and is passed in as `superclass`. The methods close over `closure`, in which the caller
+    /// should have bound `super` when there is a superclass.
+    pub(crate) fn new(
+        declaration: Stmt,
+        superclass: Option<Rc<Class>>,
+        closure: EnvironmentRef,
+    ) -> Option<Rc<Self>> {
+        match declaration {
+            Stmt::Class { name, methods, .. } => {
+                let mut method_map = HashMap::new();
+                for method in methods {
+                    let mut function = Function::new(method, Rc::clone(&closure))?;
+                    if function.name().lexeme() == "init" {
+                        function = function.into_initializer();
+                    }
+                    method_map.insert(function.name().lexeme().to_string(), function);
+                }
+                Some(Rc::new(Self {
+                    name,
+                    superclass,
+                    methods: method_map,
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &Token {
+        &self.name
+    }
+
+    /// Look up a method by name, walking up the inheritance chain if this class does not define
+    /// it itself.
+    pub(crate) fn find_method(&self, name: &str) -> Option<&Function> {
+        match self.methods.get(name) {
+            None => self.superclass.as_ref()?.find_method(name),
+            method => method,
+        }
+    }
+}
+
+// NOTE: Every instance holds on to its class, and calling a class must hand out a reference to
+// itself. That is why we implement `Callable` on the shared `Rc<Class>` rather than on `Class`.
+impl Callable for Rc<Class> {
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Literal>,
+    ) -> Result<Literal, LoxError> {
+        let instance = Literal::instance(Rc::new(RefCell::new(Instance::new(Rc::clone(self)))));
+
+        if let Some(initializer) = self.find_method("init") {
+            initializer
+                .bind(instance.clone())
+                .call(interpreter, paren, arguments)?;
+        }
+
+        Ok(instance)
+    }
+
+    fn arity(&self) -> usize {
+        self.find_method("init").map_or(0, |init| init.arity())
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    class: Rc<Class>,
+    fields: HashMap<String, Literal>,
+}
+
+impl Instance {
+    pub(crate) fn new(class: Rc<Class>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn class(&self) -> &Rc<Class> {
+        &self.class
+    }
+
+    /// Get a property from an instance.
+    ///
+    /// Fields shadow methods. A method is returned bound to the instance, so that `this` refers
+    /// to it when the method is eventually called.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the instance has neither a field nor a method with
+    /// the given name.
+    pub(crate) fn get(instance: &Rc<RefCell<Self>>, name: &Token) -> Result<Literal, LoxError> {
+        let this = instance.borrow();
+        if let Some(value) = this.fields.get(name.lexeme()) {
+            return Ok(value.clone());
+        }
+
+        if let Some(method) = this.class.find_method(name.lexeme()) {
+            let bound = method.bind(Literal::instance(Rc::clone(instance)));
+            return Ok(Literal::function(bound));
+        }
+
+        Err(
+            LoxError::from_token(name, format!("Undefined property '{}'.", name.lexeme()))
+                .with_code(Code::UndefinedProperty),
+        )
+    }
+
+    pub(crate) fn set(&mut self, name: &Token, value: Literal) {
+        self.fields.insert(name.lexeme().to_string(), value);
+    }
+}
@@ -0,0 +1,819 @@
+use std::rc::Rc;
+
+use crate::ast::{Expr, Stmt};
+use crate::chunk::{Chunk, Constant, OpCode, Prototype};
+use crate::diagnostic::Code;
+use crate::token::{Literal, Token, TokenType};
+use crate::LoxError;
+
+/// The most locals, and the most upvalues, a single function can have: slots are one byte.
+const MAX_SLOTS: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    /// The scope depth the local was declared at.
+    depth: usize,
+    /// Whether a closure captures this local, so it must be moved off the stack when it goes
+    /// out of scope.
+    is_captured: bool,
+}
+
+/// Where a closure finds one of its upvalues when it is created: either in a local slot of the
+/// enclosing function, or in an upvalue of the enclosing function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+/// The jumps out of a loop that is being compiled, which can only be patched once the end of
+/// the loop is known.
+#[derive(Debug)]
+struct Loop {
+    scope_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// The state of a function that is being compiled.
+#[derive(Debug)]
+struct FunctionState {
+    kind: FunctionKind,
+    name: String,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: String) -> Self {
+        // The first slot holds the function that is being called, or the instance a method is
+        // called on. Only the latter can be referred to, as `this`.
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        Self {
+            kind,
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+/// How a variable is reached from the function that is being compiled.
+enum Slot {
+    Local(u8),
+    Upvalue(u8),
+    Global,
+}
+
+/// Compiles a resolved syntax tree into bytecode for the [`Vm`](crate::vm::Vm).
+///
+/// Like the resolver, the compiler works out statically where every variable lives. Locals get
+/// a slot on the stack of their function, variables of enclosing functions become upvalues of
+/// the closure, and everything else is looked up by name in the globals.
+pub(crate) struct Compiler {
+    /// Stack of functions being compiled. The top-level script is at the bottom.
+    functions: Vec<FunctionState>,
+    /// The source position that emitted bytes are attributed to.
+    line: usize,
+    col: usize,
+}
+
+impl Compiler {
+    pub(crate) fn new() -> Self {
+        Self {
+            functions: vec![FunctionState::new(FunctionKind::Script, String::new())],
+            line: 0,
+            col: 0,
+        }
+    }
+
+    /// Compile `statements` into the function prototype of a script. The script returns the
+    /// value of the expression statement it ends with, or nil if it ends with another kind of
+    /// statement.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the program exceeds one of the limits of the
+    /// bytecode format, such as the number of locals in a function.
+    pub(crate) fn compile(mut self, statements: &[Stmt]) -> Result<Rc<Prototype>, LoxError> {
+        if let Some((Stmt::Expression { expression }, rest)) = statements.split_last() {
+            for statement in rest {
+                self.statement(statement)?;
+            }
+            self.expression(expression)?;
+            self.emit_op(OpCode::Return);
+            let (prototype, _) = self.pop_function();
+            return Ok(Rc::new(prototype));
+        }
+
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        let (prototype, _) = self.end_function();
+        Ok(Rc::new(prototype))
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), LoxError> {
+        match statement {
+            Stmt::Block { statements } => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+                self.end_scope();
+            }
+            Stmt::Break { keyword } => {
+                self.at(keyword);
+                let jump = self.exit_loop()?;
+                self.current_loop().breaks.push(jump);
+            }
+            Stmt::Continue { keyword } => {
+                self.at(keyword);
+                let jump = self.exit_loop()?;
+                self.current_loop().continues.push(jump);
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                self.at(name);
+                let name_constant = self.identifier_constant(name.lexeme())?;
+                self.declare_variable(name)?;
+                self.emit_with_u16(OpCode::Class, name_constant);
+                self.define_variable(name)?;
+
+                if let Some(superclass) = superclass {
+                    let Expr::Variable {
+                        name: superclass_name,
+                        ..
+                    } = superclass
+                    else {
+                        unreachable!()
+                    };
+                    self.expression(superclass)?;
+
+                    // The superclass stays on the stack, as the local `super` that methods of
+                    // the subclass capture.
+                    self.begin_scope();
+                    self.add_local("super")?;
+
+                    self.named_variable(name)?;
+                    self.at(superclass_name);
+                    let superclass_constant = self.identifier_constant(superclass_name.lexeme())?;
+                    self.emit_with_u16(OpCode::Inherit, superclass_constant);
+                }
+
+                self.named_variable(name)?;
+                for method in methods {
+                    let Stmt::Function { name, params, body } = method else {
+                        unreachable!()
+                    };
+                    let kind = if name.lexeme() == "init" {
+                        FunctionKind::Initializer
+                    } else {
+                        FunctionKind::Method
+                    };
+                    self.function(name.lexeme(), name, params, body, kind)?;
+                    let method_constant = self.identifier_constant(name.lexeme())?;
+                    self.emit_with_u16(OpCode::Method, method_constant);
+                }
+                self.emit_op(OpCode::Pop);
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+            }
+            Stmt::Expression { expression } => {
+                self.expression(expression)?;
+                self.emit_op(OpCode::Pop);
+            }
+            Stmt::Function { name, params, body } => {
+                // Declare the name before compiling the body, so that a function can refer to
+                // itself recursively.
+                self.at(name);
+                self.declare_variable(name)?;
+                self.function(name.lexeme(), name, params, body, FunctionKind::Function)?;
+                self.define_variable(name)?;
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition)?;
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch)?;
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump)?;
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch)?;
+                }
+                self.patch_jump(else_jump)?;
+            }
+            Stmt::Print { expression } => {
+                self.expression(expression)?;
+                self.emit_op(OpCode::Print);
+            }
+            Stmt::Return { keyword, value } => {
+                self.at(keyword);
+                match value {
+                    // The resolver makes sure initializers never return a value.
+                    Some(value) => self.expression(value)?,
+                    None if self.current().kind == FunctionKind::Initializer => {
+                        self.emit_with_u8(OpCode::GetLocal, 0)
+                    }
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.emit_op(OpCode::Return);
+            }
+            Stmt::Var { name, initializer } => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    None => self.emit_op(OpCode::Nil),
+                }
+
+                // A local only comes into scope after its initializer, so that the initializer
+                // can still refer to a variable it shadows.
+                self.at(name);
+                self.declare_variable(name)?;
+                self.define_variable(name)?;
+            }
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
+                let loop_start = self.chunk().code().len();
+                self.expression(condition)?;
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+
+                let scope_depth = self.current().scope_depth;
+                self.current().loops.push(Loop {
+                    scope_depth,
+                    breaks: Vec::new(),
+                    continues: Vec::new(),
+                });
+                self.statement(body)?;
+                let Loop {
+                    breaks, continues, ..
+                } = self
+                    .current()
+                    .loops
+                    .pop()
+                    .expect("the loop was pushed above");
+
+                for jump in continues {
+                    self.patch_jump(jump)?;
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment)?;
+                    self.emit_op(OpCode::Pop);
+                }
+                self.emit_loop(loop_start)?;
+
+                self.patch_jump(exit_jump)?;
+                self.emit_op(OpCode::Pop);
+                for jump in breaks {
+                    self.patch_jump(jump)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &Expr) -> Result<(), LoxError> {
+        match expression {
+            Expr::Literal { value, token } => {
+                self.at(token);
+                match value {
+                    Literal::Nil => self.emit_op(OpCode::Nil),
+                    Literal::Bool(true) => self.emit_op(OpCode::True),
+                    Literal::Bool(false) => self.emit_op(OpCode::False),
+                    Literal::Number(n) => self.emit_constant(Constant::Number(*n))?,
+                    Literal::String(s) => {
+                        self.emit_constant(Constant::String(s.as_str().into()))?
+                    }
+                    // The parser only produces literals of the types above.
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Variable { name, .. } => self.named_variable(name)?,
+            Expr::Assign { name, value, .. } => {
+                self.expression(value)?;
+                self.at(name);
+                match self.resolve(name.lexeme())? {
+                    Slot::Local(slot) => self.emit_with_u8(OpCode::SetLocal, slot),
+                    Slot::Upvalue(index) => self.emit_with_u8(OpCode::SetUpvalue, index),
+                    Slot::Global => {
+                        let name_constant = self.identifier_constant(name.lexeme())?;
+                        self.emit_with_u16(OpCode::SetGlobal, name_constant);
+                    }
+                }
+            }
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left)?;
+                self.at(operator);
+                let end_jump = match operator.token_type() {
+                    TokenType::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        end_jump
+                    }
+                    TokenType::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump)?;
+                        self.emit_op(OpCode::Pop);
+                        end_jump
+                    }
+                    _ => unreachable!(),
+                };
+                self.expression(right)?;
+                self.patch_jump(end_jump)?;
+            }
+            Expr::Unary { operator, right } => {
+                self.expression(right)?;
+                self.at(operator);
+                match operator.token_type() {
+                    TokenType::Bang => self.emit_op(OpCode::Not),
+                    TokenType::Minus => self.emit_op(OpCode::Negate),
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.at(operator);
+                match operator.token_type() {
+                    TokenType::Minus => self.emit_op(OpCode::Subtract),
+                    TokenType::Plus => self.emit_op(OpCode::Add),
+                    TokenType::Slash => self.emit_op(OpCode::Divide),
+                    TokenType::Star => self.emit_op(OpCode::Multiply),
+                    TokenType::Greater => self.emit_op(OpCode::Greater),
+                    TokenType::GreaterEqual => self.emit_op(OpCode::GreaterEqual),
+                    TokenType::Less => self.emit_op(OpCode::Less),
+                    TokenType::LessEqual => self.emit_op(OpCode::LessEqual),
+                    TokenType::BangEqual => {
+                        self.emit_op(OpCode::Equal);
+                        self.emit_op(OpCode::Not);
+                    }
+                    TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                self.expression(callee)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                self.at(paren);
+                // The parser already limits the number of arguments to what fits in a byte.
+                let argument_count = u8::try_from(arguments.len()).map_err(|_| {
+                    LoxError::from_token(paren, "Can't have more than 255 arguments.".to_string())
+                        .with_code(Code::TooManyParametersOrArguments)
+                })?;
+                self.emit_with_u8(OpCode::Call, argument_count);
+            }
+            Expr::Function {
+                keyword,
+                params,
+                body,
+            } => self.function("", keyword, params, body, FunctionKind::Function)?,
+            Expr::Grouping { expression } => self.expression(expression)?,
+            Expr::List { bracket, elements } => {
+                for element in elements {
+                    self.expression(element)?;
+                }
+                self.at(bracket);
+                let count = u16::try_from(elements.len())
+                    .map_err(|_| self.error(Code::TooMany, "Too many elements in list literal."))?;
+                self.emit_with_u16(OpCode::BuildList, count);
+            }
+            Expr::Map { brace, entries } => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+                self.at(brace);
+                let count = u16::try_from(entries.len())
+                    .map_err(|_| self.error(Code::TooMany, "Too many entries in map literal."))?;
+                self.emit_with_u16(OpCode::BuildMap, count);
+            }
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.at(bracket);
+                self.emit_op(OpCode::GetIndex);
+            }
+            Expr::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+                self.at(bracket);
+                self.emit_op(OpCode::SetIndex);
+            }
+            Expr::Get { object, name } => {
+                self.expression(object)?;
+                self.at(name);
+                let name_constant = self.identifier_constant(name.lexeme())?;
+                self.emit_with_u16(OpCode::GetProperty, name_constant);
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(value)?;
+                self.at(name);
+                let name_constant = self.identifier_constant(name.lexeme())?;
+                self.emit_with_u16(OpCode::SetProperty, name_constant);
+            }
+            Expr::Super {
+                keyword, method, ..
+            } => {
+                self.at(keyword);
+                self.named_variable_str("this")?;
+                self.named_variable_str("super")?;
+                self.at(method);
+                let method_constant = self.identifier_constant(method.lexeme())?;
+                self.emit_with_u16(OpCode::GetSuper, method_constant);
+            }
+            Expr::This { keyword, .. } => {
+                self.at(keyword);
+                self.named_variable_str("this")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compile the body of a function, and emit the instruction that creates a closure of it.
+    /// Anonymous functions have an empty `name`, and are located by their `fun` keyword instead.
+    fn function(
+        &mut self,
+        name: &str,
+        token: &Token,
+        params: &[Token],
+        body: &[Stmt],
+        kind: FunctionKind,
+    ) -> Result<(), LoxError> {
+        self.functions
+            .push(FunctionState::new(kind, name.to_string()));
+        self.begin_scope();
+
+        for param in params {
+            self.current().arity += 1;
+            self.at(param);
+            self.declare_variable(param)?;
+        }
+        for statement in body {
+            self.statement(statement)?;
+        }
+
+        let (prototype, upvalues) = self.end_function();
+        self.at(token);
+        let function_constant = self.make_constant(Constant::Function(Rc::new(prototype)))?;
+        self.emit_with_u16(OpCode::Closure, function_constant);
+        for upvalue in upvalues {
+            self.emit(upvalue.is_local as u8);
+            self.emit(upvalue.index);
+        }
+
+        Ok(())
+    }
+
+    /// Finish the function on top of the stack, returning its prototype and the upvalues its
+    /// closures capture.
+    fn end_function(&mut self) -> (Prototype, Vec<UpvalueRef>) {
+        self.emit_return();
+        self.pop_function()
+    }
+
+    /// Finish compiling the current function, which must already end with a return.
+    fn pop_function(&mut self) -> (Prototype, Vec<UpvalueRef>) {
+        let function = self
+            .functions
+            .pop()
+            .expect("there is always a function being compiled");
+        let prototype = Prototype {
+            name: function.name,
+            arity: function.arity,
+            upvalue_count: function.upvalues.len(),
+            chunk: function.chunk,
+        };
+        (prototype, function.upvalues)
+    }
+
+    fn emit_return(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit_with_u8(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    fn named_variable(&mut self, name: &Token) -> Result<(), LoxError> {
+        self.at(name);
+        self.named_variable_str(name.lexeme())
+    }
+
+    fn named_variable_str(&mut self, name: &str) -> Result<(), LoxError> {
+        match self.resolve(name)? {
+            Slot::Local(slot) => self.emit_with_u8(OpCode::GetLocal, slot),
+            Slot::Upvalue(index) => self.emit_with_u8(OpCode::GetUpvalue, index),
+            Slot::Global => {
+                let name_constant = self.identifier_constant(name)?;
+                self.emit_with_u16(OpCode::GetGlobal, name_constant);
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&mut self, name: &str) -> Result<Slot, LoxError> {
+        let function = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(function, name) {
+            return Ok(Slot::Local(slot));
+        }
+        if let Some(index) = self.resolve_upvalue(function, name)? {
+            return Ok(Slot::Upvalue(index));
+        }
+        Ok(Slot::Global)
+    }
+
+    fn resolve_local(&self, function: usize, name: &str) -> Option<u8> {
+        self.functions[function]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
+            // There are never more locals than fit in a byte, see `add_local`.
+            .map(|slot| slot as u8)
+    }
+
+    /// Find `name` in the functions enclosing `function`, and thread it through the upvalues of
+    /// every function in between.
+    fn resolve_upvalue(&mut self, function: usize, name: &str) -> Result<Option<u8>, LoxError> {
+        if function == 0 {
+            return Ok(None);
+        }
+
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            self.functions[function - 1].locals[slot as usize].is_captured = true;
+            return self.add_upvalue(function, slot, true).map(Some);
+        }
+        if let Some(index) = self.resolve_upvalue(function - 1, name)? {
+            return self.add_upvalue(function, index, false).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, function: usize, index: u8, is_local: bool) -> Result<u8, LoxError> {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &mut self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(existing as u8);
+        }
+
+        if upvalues.len() == MAX_SLOTS {
+            return Err(self.error(Code::TooMany, "Too many closure variables in function."));
+        }
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    /// Declare a local variable for `name`, unless we are in the global scope.
+    fn declare_variable(&mut self, name: &Token) -> Result<(), LoxError> {
+        if self.current().scope_depth > 0 {
+            self.add_local(name.lexeme())?;
+        }
+        Ok(())
+    }
+
+    /// Define the global `name` to the value on top of the stack, unless we are in a local
+    /// scope, where that value already is the variable.
+    fn define_variable(&mut self, name: &Token) -> Result<(), LoxError> {
+        if self.current().scope_depth == 0 {
+            let name_constant = self.identifier_constant(name.lexeme())?;
+            self.emit_with_u16(OpCode::DefineGlobal, name_constant);
+        }
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: &str) -> Result<(), LoxError> {
+        if self.current().locals.len() == MAX_SLOTS {
+            return Err(self.error(Code::TooMany, "Too many local variables in function."));
+        }
+
+        let depth = self.current().scope_depth;
+        self.current().locals.push(Local {
+            name: name.to_string(),
+            depth,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current().scope_depth -= 1;
+        let depth = self.current().scope_depth;
+        self.discard_locals(depth);
+
+        let locals = &mut self.current().locals;
+        while locals.last().is_some_and(|local| local.depth > depth) {
+            locals.pop();
+        }
+    }
+
+    /// Emit the instructions that remove all locals deeper than `depth` from the stack, without
+    /// forgetting about them at compile time.
+    fn discard_locals(&mut self, depth: usize) {
+        let captured: Vec<bool> = self
+            .current()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| local.is_captured)
+            .collect();
+
+        for is_captured in captured {
+            if is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    /// Discard the locals of the loop body that is being left by a `break` or `continue`, and
+    /// emit the jump out of it, returning the offset of the jump to patch later.
+    fn exit_loop(&mut self) -> Result<usize, LoxError> {
+        let Some(depth) = self.current().loops.last().map(|l| l.scope_depth) else {
+            // The parser rejects `break` and `continue` outside of loops.
+            return Err(self.error(Code::JumpOutsideLoop, "Can't jump outside of a loop."));
+        };
+        self.discard_locals(depth);
+        Ok(self.emit_jump(OpCode::Jump))
+    }
+
+    fn current_loop(&mut self) -> &mut Loop {
+        self.current()
+            .loops
+            .last_mut()
+            .expect("exit_loop checked we are in a loop")
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("there is always a function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().chunk
+    }
+
+    /// Attribute the bytes emitted from now on to `token`.
+    fn at(&mut self, token: &Token) {
+        self.line = token.line();
+        self.col = token.col();
+    }
+
+    fn error(&self, code: Code, message: &str) -> LoxError {
+        LoxError::new(self.line, self.col, message.to_string()).with_code(code)
+    }
+
+    fn emit(&mut self, byte: u8) {
+        let (line, col) = (self.line, self.col);
+        self.chunk().write(byte, line, col);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit(op as u8);
+    }
+
+    fn emit_with_u8(&mut self, op: OpCode, operand: u8) {
+        self.emit_op(op);
+        self.emit(operand);
+    }
+
+    fn emit_with_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit_op(op);
+        self.emit_u16(operand);
+    }
+
+    fn emit_u16(&mut self, operand: u16) {
+        for byte in operand.to_be_bytes() {
+            self.emit(byte);
+        }
+    }
+
+    fn emit_constant(&mut self, constant: Constant) -> Result<(), LoxError> {
+        let index = self.make_constant(constant)?;
+        self.emit_with_u16(OpCode::Constant, index);
+        Ok(())
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> Result<u16, LoxError> {
+        let index = self.chunk().add_constant(constant);
+        u16::try_from(index)
+            .map_err(|_| self.error(Code::TooMany, "Too many constants in one chunk."))
+    }
+
+    /// The constant holding `name`, reusing an existing one if the chunk already has it.
+    fn identifier_constant(&mut self, name: &str) -> Result<u16, LoxError> {
+        let existing = self
+            .chunk()
+            .constants()
+            .iter()
+            .position(|constant| matches!(constant, Constant::String(s) if &**s == name));
+        match existing {
+            // Existing constants were all given a valid index by `make_constant`.
+            Some(index) => Ok(index as u16),
+            None => self.make_constant(Constant::String(name.into())),
+        }
+    }
+
+    /// Emit a forward jump with a placeholder offset, returning where to patch it.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_with_u16(op, u16::MAX);
+        self.chunk().code().len() - 2
+    }
+
+    /// Point the forward jump at `offset` to the next instruction that will be emitted.
+    fn patch_jump(&mut self, offset: usize) -> Result<(), LoxError> {
+        let distance = self.chunk().code().len() - offset - 2;
+        let distance = u16::try_from(distance)
+            .map_err(|_| self.error(Code::JumpTooFar, "Too much code to jump over."))?;
+        let [high, low] = distance.to_be_bytes();
+        self.chunk().patch(offset, high);
+        self.chunk().patch(offset + 1, low);
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), LoxError> {
+        self.emit_op(OpCode::Loop);
+        let distance = self.chunk().code().len() - loop_start + 2;
+        let distance = u16::try_from(distance)
+            .map_err(|_| self.error(Code::JumpTooFar, "Loop body too large."))?;
+        self.emit_u16(distance);
+        Ok(())
+    }
+}
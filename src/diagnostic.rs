@@ -0,0 +1,285 @@
+//! Error codes, and the rendering of errors for people to read.
+//!
+//! A rendered error looks like this:
+//!
+//! ```text
+//! error[E0001]: Expect expression.
+//!  --> line 1, col 9
+//!   |
+//! 1 | var a = ;
+//!   |         ^
+//! ```
+//!
+//! Errors can also be written as JSON, for tools to read; see [`json`].
+
+use std::fmt::Write;
+use std::iter::once;
+
+use crate::{Frame, LoxError};
+
+/// The kinds of errors that have a stable code, which is attached to an error where it is raised.
+///
+/// Codes are never renumbered or reused, so that they can be searched for and documented. New
+/// kinds of errors get new codes: `E00xx` for errors found before the program runs, and `E01xx`
+/// for runtime errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Code {
+    // Scanning and parsing.
+    ExpectExpression,
+    UnexpectedCharacter,
+    UnterminatedString,
+    /// A missing `;`.
+    ExpectSemicolon,
+    /// Some other missing punctuation or keyword.
+    ExpectToken,
+    /// A missing name of a variable, function, class, property or parameter.
+    ExpectName,
+    InvalidAssignmentTarget,
+    /// More than 255 parameters or arguments.
+    TooManyParametersOrArguments,
+    /// `break` or `continue` outside of a loop.
+    JumpOutsideLoop,
+    // Resolving.
+    AlreadyDeclared,
+    ReadInOwnInitializer,
+    ReturnFromTopLevel,
+    ReturnValueFromInitializer,
+    InheritFromSelf,
+    ThisOutsideClass,
+    SuperOutsideClass,
+    SuperWithoutSuperclass,
+    // Compiling to bytecode.
+    /// Too many constants, locals, upvalues, or elements of a literal for the instruction set.
+    TooMany,
+    /// A jump or loop longer than its operand can hold.
+    JumpTooFar,
+    // Running.
+    UndefinedVariable,
+    UndefinedProperty,
+    UndefinedKey,
+    /// An operand of the wrong type.
+    UnexpectedType,
+    NotCallable,
+    WrongArity,
+    NotAnInstance,
+    SuperclassNotClass,
+    NotIndexable,
+    InvalidListIndex,
+    InvalidMapKey,
+    StackOverflow,
+}
+
+impl Code {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Code::ExpectExpression => "E0001",
+            Code::UnexpectedCharacter => "E0002",
+            Code::UnterminatedString => "E0003",
+            Code::ExpectSemicolon => "E0004",
+            Code::ExpectToken => "E0005",
+            Code::ExpectName => "E0006",
+            Code::InvalidAssignmentTarget => "E0007",
+            Code::TooManyParametersOrArguments => "E0008",
+            Code::JumpOutsideLoop => "E0009",
+            Code::AlreadyDeclared => "E0010",
+            Code::ReadInOwnInitializer => "E0011",
+            Code::ReturnFromTopLevel => "E0012",
+            Code::ReturnValueFromInitializer => "E0013",
+            Code::InheritFromSelf => "E0014",
+            Code::ThisOutsideClass => "E0015",
+            Code::SuperOutsideClass => "E0016",
+            Code::SuperWithoutSuperclass => "E0017",
+            Code::TooMany => "E0018",
+            Code::JumpTooFar => "E0019",
+            Code::UndefinedVariable => "E0100",
+            Code::UndefinedProperty => "E0101",
+            Code::UndefinedKey => "E0102",
+            Code::UnexpectedType => "E0103",
+            Code::NotCallable => "E0104",
+            Code::WrongArity => "E0105",
+            Code::NotAnInstance => "E0106",
+            Code::SuperclassNotClass => "E0107",
+            Code::NotIndexable => "E0108",
+            Code::InvalidListIndex => "E0109",
+            Code::InvalidMapKey => "E0110",
+            Code::StackOverflow => "E0111",
+        }
+    }
+}
+
+/// Render `error`, followed by the errors reported with it, quoting the lines of `source` they
+/// occurred at. The first line of `source` is line `first_line`.
+pub(crate) fn render(error: &LoxError, source: &str, first_line: usize) -> String {
+    let mut rendered = String::new();
+    for (i, error) in once(error).chain(error.others()).enumerate() {
+        if i > 0 {
+            rendered.push('\n');
+        }
+        render_one(&mut rendered, error, source, first_line)
+            .expect("writing to a String can't fail");
+    }
+    rendered
+}
+
+fn render_one(
+    out: &mut String,
+    error: &LoxError,
+    source: &str,
+    first_line: usize,
+) -> std::fmt::Result {
+    match error.code() {
+        Some(code) => writeln!(out, "error[{code}]: {}", error.message())?,
+        None => writeln!(out, "error: {}", error.message())?,
+    }
+
+    // The gutter holds line numbers, so it is as wide as the widest of them.
+    let gutter = " ".repeat(error.line().to_string().len());
+    if error.line() > 0 {
+        writeln!(
+            out,
+            "{gutter}--> line {}, col {}",
+            error.line(),
+            error.col()
+        )?;
+        let text = error
+            .line()
+            .checked_sub(first_line)
+            .and_then(|index| source.lines().nth(index));
+        if let Some(text) = text {
+            writeln!(out, "{gutter} |")?;
+            writeln!(out, "{} | {text}", error.line())?;
+            writeln!(
+                out,
+                "{gutter} | {}",
+                underline(text, error.col(), error.end_col())
+            )?;
+        }
+    }
+
+    for note in error.notes() {
+        writeln!(out, "{gutter} = note: {note}")?;
+    }
+
+    if !error.trace().is_empty() {
+        write_trace(out, error.trace(), &format!("{gutter}   "))?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Write `error`, followed by the errors reported with it, as JSON objects, one per line.
+/// `file` is the path of the script the errors occurred in, if they came from one.
+///
+/// Each object looks like this, with `null` for the location of an error that has none, and
+/// for the code of an error that has none, and for the function name of an anonymous function:
+///
+/// ```text
+/// {"file":"a.lox","line":2,"col":5,"span":{"start":{"line":2,"col":5},"end":{"line":2,"col":7}},
+///  "severity":"error","code":"E0100","message":"Undefined variable 'foo'.","notes":[],
+///  "frames":[{"function":"f","line":4,"col":3}]}
+/// ```
+///
+/// The frames are those of the trace of a runtime error, innermost first.
+pub(crate) fn json(error: &LoxError, file: Option<&str>) -> String {
+    let mut json = String::new();
+    for error in once(error).chain(error.others()) {
+        json_one(&mut json, error, file).expect("writing to a String can't fail");
+        json.push('\n');
+    }
+    json
+}
+
+fn json_one(out: &mut String, error: &LoxError, file: Option<&str>) -> std::fmt::Result {
+    write!(
+        out,
+        "{{\"file\":{}",
+        file.map_or("null".to_string(), json_string)
+    )?;
+    if error.line() > 0 {
+        let (line, col, end_col) = (error.line(), error.col(), error.end_col());
+        write!(out, ",\"line\":{line},\"col\":{col}")?;
+        write!(
+            out,
+            ",\"span\":{{\"start\":{{\"line\":{line},\"col\":{col}}}"
+        )?;
+        write!(out, ",\"end\":{{\"line\":{line},\"col\":{end_col}}}}}")?;
+    } else {
+        write!(out, ",\"line\":null,\"col\":null,\"span\":null")?;
+    }
+    write!(out, ",\"severity\":\"error\"")?;
+    let code = error.code().map_or("null".to_string(), json_string);
+    write!(
+        out,
+        ",\"code\":{code},\"message\":{}",
+        json_string(error.message())
+    )?;
+
+    let notes: Vec<String> = error.notes().iter().map(|note| json_string(note)).collect();
+    write!(out, ",\"notes\":[{}]", notes.join(","))?;
+
+    let frames: Vec<String> = error
+        .trace()
+        .iter()
+        .map(|frame| {
+            let function = match frame.function() {
+                "" => "null".to_string(),
+                function => json_string(function),
+            };
+            format!(
+                "{{\"function\":{function},\"line\":{},\"col\":{}}}",
+                frame.line(),
+                frame.col()
+            )
+        })
+        .collect();
+    write!(out, ",\"frames\":[{}]}}", frames.join(","))
+}
+
+/// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                write!(json, "\\u{:04x}", c as u32).expect("writing to a String can't fail")
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// How many frames of a trace are shown.
+const MAX_TRACE_FRAMES: usize = 16;
+
+/// Write `trace` one frame per line, ending with the script, each line starting with `indent`.
+/// There is no newline after the last line.
+pub(crate) fn write_trace(out: &mut impl Write, trace: &[Frame], indent: &str) -> std::fmt::Result {
+    // Deep recursion can leave a thousand frames behind; the innermost ones tell the story.
+    for frame in trace.iter().take(MAX_TRACE_FRAMES) {
+        writeln!(out, "{indent}{frame}")?;
+    }
+    if trace.len() > MAX_TRACE_FRAMES {
+        writeln!(out, "{indent}... {} more", trace.len() - MAX_TRACE_FRAMES)?;
+    }
+    write!(out, "{indent}at script")
+}
+
+/// A line of carets under columns `col` through `end_col` of `text`. Tabs before the carets are
+/// kept, so that they line up however wide the tabs are shown.
+fn underline(text: &str, col: usize, end_col: usize) -> String {
+    let indent: String = text
+        .chars()
+        .take(col.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = end_col.saturating_sub(col) + 1;
+    format!("{indent}{}", "^".repeat(width))
+}
@@ -0,0 +1,141 @@
+//! A human-readable listing of compiled bytecode.
+
+use std::fmt::{self, Write};
+
+use crate::chunk::{Chunk, Constant, OpCode, Prototype};
+
+/// Disassemble the script `prototype`, followed by every function nested inside of it.
+///
+/// Every instruction is listed with its offset, the source line it was compiled from (or `|`
+/// if that is the same as for the previous instruction), and its decoded operands.
+pub(crate) fn disassemble(prototype: &Prototype) -> String {
+    let mut listing = String::new();
+    disassemble_into(&mut listing, prototype, "<script>").expect("writing to a String can't fail");
+    listing
+}
+
+fn disassemble_into(listing: &mut String, prototype: &Prototype, title: &str) -> fmt::Result {
+    writeln!(listing, "== {title} ==")?;
+
+    let chunk = &prototype.chunk;
+    let mut offset = 0;
+    let mut previous_line = None;
+    while offset < chunk.code().len() {
+        let (line, _) = chunk.position(offset);
+        if previous_line == Some(line) {
+            write!(listing, "{offset:04}    | ")?;
+        } else {
+            write!(listing, "{offset:04} {line:4} ")?;
+        }
+        previous_line = Some(line);
+
+        offset = instruction(listing, chunk, offset)?;
+    }
+
+    for constant in chunk.constants() {
+        if let Constant::Function(function) = constant {
+            writeln!(listing)?;
+            disassemble_into(listing, function, &function.to_string())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the instruction at `offset` on a line of its own, and return the offset of the next
+/// instruction.
+fn instruction(listing: &mut String, chunk: &Chunk, offset: usize) -> Result<usize, fmt::Error> {
+    let code = chunk.code();
+    // Operands of a truncated instruction read as zero, rather than bringing the listing down.
+    let byte = |at: usize| code.get(at).copied().unwrap_or_default();
+    let short = |at: usize| u16::from_be_bytes([byte(at), byte(at + 1)]) as usize;
+
+    let Some(op) = OpCode::from_byte(code[offset]) else {
+        writeln!(listing, "Unknown opcode {}", code[offset])?;
+        return Ok(offset + 1);
+    };
+    let name = format!("{op:?}");
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Inherit
+        | OpCode::Method => {
+            let index = short(offset + 1);
+            writeln!(listing, "{name:<16} {index:4} {}", constant(chunk, index))?;
+            Ok(offset + 3)
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => {
+            writeln!(listing, "{name:<16} {:4}", byte(offset + 1))?;
+            Ok(offset + 2)
+        }
+        OpCode::BuildList | OpCode::BuildMap => {
+            writeln!(listing, "{name:<16} {:4}", short(offset + 1))?;
+            Ok(offset + 3)
+        }
+        OpCode::Jump | OpCode::JumpIfFalse => {
+            let target = offset + 3 + short(offset + 1);
+            writeln!(listing, "{name:<16} {offset:4} -> {target:04}")?;
+            Ok(offset + 3)
+        }
+        OpCode::Loop => {
+            let target = (offset + 3).saturating_sub(short(offset + 1));
+            writeln!(listing, "{name:<16} {offset:4} -> {target:04}")?;
+            Ok(offset + 3)
+        }
+        OpCode::Closure => {
+            let index = short(offset + 1);
+            writeln!(listing, "{name:<16} {index:4} {}", constant(chunk, index))?;
+
+            let mut next = offset + 3;
+            if let Some(Constant::Function(function)) = chunk.constants().get(index) {
+                for _ in 0..function.upvalue_count {
+                    let kind = if byte(next) == 1 { "local" } else { "upvalue" };
+                    writeln!(listing, "{next:04}    |   {kind:<14} {:4}", byte(next + 1))?;
+                    next += 2;
+                }
+            }
+            Ok(next)
+        }
+        OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Pop
+        | OpCode::GetIndex
+        | OpCode::SetIndex
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::Print
+        | OpCode::CloseUpvalue
+        | OpCode::Return => {
+            writeln!(listing, "{name}")?;
+            Ok(offset + 1)
+        }
+    }
+}
+
+fn constant(chunk: &Chunk, index: usize) -> String {
+    match chunk.constants().get(index) {
+        Some(constant) => constant.to_string(),
+        None => "<invalid constant>".to_string(),
+    }
+}
@@ -1,13 +1,20 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
+use crate::callable::NativeFunction;
+use crate::diagnostic::Code;
 use crate::token::{Literal, Token};
 use crate::LoxError;
 
 type Object = Literal;
 
+/// A handle to an environment that may be shared between scopes and closures.
+pub(crate) type EnvironmentRef = Rc<RefCell<Environment>>;
+
 #[derive(Debug, Clone)]
 pub(crate) struct Environment {
-    fallback: Option<Box<Self>>,
+    fallback: Option<EnvironmentRef>,
     values: HashMap<String, Object>,
 }
 
@@ -19,15 +26,20 @@ impl Environment {
         }
     }
 
-    pub(crate) fn from_parent(environment: &Environment) -> Self {
+    /// Create a new environment that is enclosed by `environment`.
+    ///
+    /// The enclosing environment is shared, not copied, so assignments made through the new
+    /// environment are visible to everyone else holding on to `environment`.
+    pub(crate) fn from_parent(environment: &EnvironmentRef) -> Self {
         Self {
-            fallback: Some(Box::new(environment.clone())),
+            fallback: Some(Rc::clone(environment)),
             values: HashMap::new(),
         }
     }
 
-    pub(crate) fn fallback(self) -> Option<Environment> {
-        self.fallback.map(|env| *env)
+    /// Wrap this environment in a shareable [`EnvironmentRef`].
+    pub(crate) fn shared(self) -> EnvironmentRef {
+        Rc::new(RefCell::new(self))
     }
 }
 
@@ -36,25 +48,100 @@ impl Environment {
         self.values.insert(name, value);
     }
 
+    /// The names bound in this environment, not counting the enclosing ones, with their values,
+    /// sorted by name.
+    pub(crate) fn bindings(&self) -> Vec<(String, Object)> {
+        let mut bindings: Vec<_> = self
+            .values
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        bindings.sort_by(|(a, _), (b, _)| a.cmp(b));
+        bindings
+    }
+
+    /// Define a native function under its own name.
+    pub(crate) fn define_native(&mut self, native: NativeFunction) {
+        let name = native.name().to_string();
+        self.define(name, Literal::Native(Rc::new(native)));
+    }
+
+    /// Get the Literal value bound to a name, looking through the enclosing environments if it is
+    /// not bound in this one.
+    pub(crate) fn get(&self, name: &str) -> Option<Object> {
+        match self.values.get(name) {
+            // If the name is not bound here, try to get it from the enclosing `fallback`
+            // environment, if there is one.
+            None => self.fallback.as_ref()?.borrow().get(name),
+            value => value.cloned(),
+        }
+    }
+
     /// Get the Literal value bound to a variable.
     ///
     /// # Errors
     ///
     /// This function will return an error if the variable is not found.
-    pub(crate) fn get_var(&self, name: &Token) -> Result<&Object, LoxError> {
-        let lexeme = name.lexeme().to_owned();
-        match self.fallback {
-            // If there is no enclosing `fallback` environment, get the variable name from this
-            // environment.
-            None => self.values.get(&lexeme),
-            // Otherwise, try to get it from this environment, but when it is not present, get it
-            // from the enclosing environment.
-            Some(ref fallback) => match self.values.get(&lexeme) {
-                None => return fallback.get_var(name),
-                value => value,
-            },
+    pub(crate) fn get_var(&self, name: &Token) -> Result<Object, LoxError> {
+        let lexeme = name.lexeme();
+        self.get(lexeme).ok_or_else(|| {
+            LoxError::from_token(name, format!("Undefined variable '{lexeme}'."))
+                .with_code(Code::UndefinedVariable)
+        })
+    }
+
+    /// Get the Literal value bound to a variable in the environment `distance` hops up the
+    /// chain of enclosing environments.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the variable is not found at that distance.
+    pub(crate) fn get_at(&self, distance: usize, name: &Token) -> Result<Object, LoxError> {
+        if distance > 0 {
+            if let Some(ref fallback) = self.fallback {
+                return fallback.borrow().get_at(distance - 1, name);
+            }
+        }
+
+        let lexeme = name.lexeme();
+        match self.values.get(lexeme) {
+            Some(value) if distance == 0 => Ok(value.clone()),
+            _ => Err(
+                LoxError::from_token(name, format!("Undefined variable '{lexeme}'."))
+                    .with_code(Code::UndefinedVariable),
+            ),
+        }
+    }
+
+    /// Assign another Literal value to a variable in the environment `distance` hops up the
+    /// chain of enclosing environments.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the variable is not found at that distance.
+    pub(crate) fn assign_at(
+        &mut self,
+        distance: usize,
+        name: Token,
+        value: Literal,
+    ) -> Result<Literal, LoxError> {
+        if distance > 0 {
+            if let Some(ref fallback) = self.fallback {
+                return fallback.borrow_mut().assign_at(distance - 1, name, value);
+            }
+        }
+
+        let lexeme = name.lexeme();
+        match self.values.get_mut(lexeme) {
+            Some(slot) if distance == 0 => {
+                *slot = value.clone();
+                Ok(value)
+            }
+            _ => Err(
+                LoxError::from_token(&name, format!("Undefined variable '{lexeme}'."))
+                    .with_code(Code::UndefinedVariable),
+            ),
         }
-        .ok_or_else(|| LoxError::from_token(name, format!("Undefined variable '{lexeme}'.")))
     }
 
     /// Assign another Literal value to a variable.
@@ -64,22 +151,22 @@ impl Environment {
     /// This function will return an error if the variable is not found.
     pub(crate) fn assign(&mut self, name: Token, value: Literal) -> Result<Literal, LoxError> {
         let lexeme = name.lexeme().to_owned();
-        if self.values.contains_key(&lexeme) {
+        if let Some(slot) = self.values.get_mut(&lexeme) {
             // The variable exists in the current scope. Nice. We assign the value to this
             // variable and return the value.
-            self.values.insert(lexeme, value.clone());
+            *slot = value.clone();
             return Ok(value);
         }
 
         // The variable does not exist in the current scope. Let's try whether it is in the
         // previous scope.
-        if let Some(ref mut fallback) = self.fallback {
-            return fallback.assign(name, value);
+        if let Some(ref fallback) = self.fallback {
+            return fallback.borrow_mut().assign(name, value);
         }
 
-        Err(LoxError::from_token(
-            &name,
-            format!("Undefined variable '{lexeme}'."),
-        ))
+        Err(
+            LoxError::from_token(&name, format!("Undefined variable '{lexeme}'."))
+                .with_code(Code::UndefinedVariable),
+        )
     }
 }
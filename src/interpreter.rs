@@ -1,34 +1,68 @@
-use crate::ast::{Expr, Stmt};
-use crate::callable::{Callable, Function};
-use crate::environment::Environment;
-use crate::token::{Literal, TokenType};
-use crate::LoxError;
+use std::fmt::Debug;
+use std::io::{stdout, Write};
+use std::rc::Rc;
 
+use crate::ast::{Depth, Expr, Stmt};
+use crate::callable::Function;
+use crate::class::{Class, Instance};
+use crate::diagnostic::Code;
+use crate::environment::{Environment, EnvironmentRef};
+use crate::map::Map;
+use crate::native;
+use crate::token::{Literal, Token, TokenType};
+use crate::vm::FRAMES_MAX;
+use crate::{Frame, LoxError};
+
+/// How the execution of a statement completed.
 #[derive(Debug, Clone)]
+pub(crate) enum ControlFlow {
+    /// The statement ran to completion.
+    Normal,
+    /// A `return` statement was executed, with the value to hand back to the caller.
+    Return(Literal),
+    /// A `break` statement was executed.
+    Break,
+    /// A `continue` statement was executed.
+    Continue,
+}
+
 pub(crate) struct Interpreter {
-    globals: Box<Environment>,
-    backtrace: Vec<Expr>,
-    return_value: Option<Literal>,
+    globals: EnvironmentRef,
+    /// The calls to Lox functions in progress, outermost first. The frames of calls that fail
+    /// with an error are left behind, for `interpret` to attach to the error.
+    frames: Vec<Frame>,
+    /// Where `print` statements write to.
+    output: Box<dyn Write>,
     // environment: Environment,
 }
 
 impl Interpreter {
     pub(crate) fn new() -> Self {
+        let mut globals = Environment::new();
+        native::define_prelude(&mut globals);
+
         Self {
-            globals: Box::new(Environment::new()), // environment: Environment::new(),
-            backtrace: Vec::new(),
-            return_value: None,
+            globals: globals.shared(), // environment: Environment::new(),
+            frames: Vec::new(),
+            output: Box::new(stdout()),
         }
     }
 
-    fn evaluate(&mut self, expr: Expr, environment: &mut Environment) -> Result<Literal, LoxError> {
+    pub(crate) fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    fn evaluate(&mut self, expr: Expr, environment: &EnvironmentRef) -> Result<Literal, LoxError> {
         match expr {
-            Expr::Literal { value } => Ok(value),
+            Expr::Literal { value, .. } => Ok(value),
             // TODO: I don't know whether this is right but we'll see.
-            Expr::Variable { ref name } => environment.get_var(name).cloned(),
-            Expr::Assign { name, value } => {
+            Expr::Variable { ref name, depth } => self.look_up_variable(name, depth, environment),
+            Expr::Assign { name, value, depth } => {
                 let value = self.evaluate(*value, environment)?;
-                environment.assign(name, value)
+                match depth {
+                    Some(distance) => environment.borrow_mut().assign_at(distance, name, value),
+                    None => self.globals.borrow_mut().assign(name, value),
+                }
             }
             Expr::Logical {
                 left,
@@ -114,8 +148,8 @@ impl Interpreter {
                         use Literal::*;
                         match (left, right) {
                             (Number(l), Number(r)) => Some(Bool(l > r)),
-                            (Bool(l), Bool(r)) => Some(Bool(l > r)),
-                            (l, r) => Some(Bool(l.is_truthy() > r.is_truthy())),
+                            (Bool(l), Bool(r)) => Some(Bool(l & !r)),
+                            (l, r) => Some(Bool(l.is_truthy() & !r.is_truthy())),
                         }
                         .ok_or(LoxError::unexpected_type(&operator))
                     }
@@ -132,8 +166,8 @@ impl Interpreter {
                         use Literal::*;
                         match (left, right) {
                             (Number(l), Number(r)) => Some(Bool(l < r)),
-                            (Bool(l), Bool(r)) => Some(Bool(l < r)),
-                            (l, r) => Some(Bool(l.is_truthy() < r.is_truthy())),
+                            (Bool(l), Bool(r)) => Some(Bool(!l & r)),
+                            (l, r) => Some(Bool(!l.is_truthy() & r.is_truthy())),
                         }
                         .ok_or(LoxError::unexpected_type(&operator))
                     }
@@ -167,10 +201,13 @@ impl Interpreter {
                 }
                 let arguments = argument_literals;
 
-                let function = callee.callable().ok_or(LoxError::from_token(
-                    &paren,
-                    "Can only call functions and classes.".to_string(),
-                ))?;
+                let function = callee.callable().ok_or(
+                    LoxError::from_token(
+                        &paren,
+                        "Can only call functions and classes.".to_string(),
+                    )
+                    .with_code(Code::NotCallable),
+                )?;
 
                 if arguments.len() != function.arity() {
                     return Err(LoxError::from_token(
@@ -180,70 +217,242 @@ impl Interpreter {
                             arity = function.arity(),
                             len = arguments.len()
                         ),
-                    ));
+                    )
+                    .with_code(Code::WrongArity));
                 }
 
-                match function.call(self, environment, arguments) {
-                    Ok(v) => Ok(v),
-                    Err(e) if e.message == "RETURN".to_string() => {
-                        let return_value = self.return_value.clone().unwrap();
-                        self.return_value = None;
-                        Ok(return_value)
-                    }
-                    Err(e) => Err(e),
-                }
+                function.call(self, &paren, arguments)
+            }
+            Expr::Function {
+                keyword,
+                params,
+                body,
+            } => {
+                let function = Function::anonymous(keyword, &params, body, Rc::clone(environment));
+                Ok(Literal::function(function))
             }
             Expr::Grouping { expression } => self.evaluate(*expression, environment),
+            Expr::List { elements, .. } => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements {
+                    values.push(self.evaluate(element, environment)?);
+                }
+                Ok(Literal::from(values))
+            }
+            Expr::Map { brace, entries } => {
+                let mut map = Map::new();
+                for (key, value) in entries {
+                    let key = self.evaluate(key, environment)?;
+                    let value = self.evaluate(value, environment)?;
+                    map.insert(key, value).map_err(|error| {
+                        error.located(|message| LoxError::from_token(&brace, message))
+                    })?;
+                }
+                Ok(Literal::from(map))
+            }
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                let object = self.evaluate(*object, environment)?;
+                let index = self.evaluate(*index, environment)?;
+                object.get_index(&index).map_err(|error| {
+                    error.located(|message| LoxError::from_token(&bracket, message))
+                })
+            }
+            Expr::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let object = self.evaluate(*object, environment)?;
+                let index = self.evaluate(*index, environment)?;
+                let value = self.evaluate(*value, environment)?;
+                object.set_index(index, value.clone()).map_err(|error| {
+                    error.located(|message| LoxError::from_token(&bracket, message))
+                })?;
+                Ok(value)
+            }
+            Expr::Get { object, name } => {
+                match self.evaluate(*object, environment)?.as_instance() {
+                    Some(instance) => Instance::get(instance, &name),
+                    None => Err(LoxError::from_token(
+                        &name,
+                        "Only instances have properties.".to_string(),
+                    )
+                    .with_code(Code::NotAnInstance)),
+                }
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                let object = self.evaluate(*object, environment)?;
+                let Some(instance) = object.as_instance() else {
+                    return Err(LoxError::from_token(
+                        &name,
+                        "Only instances have fields.".to_string(),
+                    )
+                    .with_code(Code::NotAnInstance));
+                };
+                let value = self.evaluate(*value, environment)?;
+                instance.borrow_mut().set(&name, value.clone());
+                Ok(value)
+            }
+            Expr::Super {
+                keyword,
+                method,
+                depth,
+            } => {
+                let superclass = self.look_up_variable(&keyword, depth, environment)?;
+                let Some(superclass) = superclass.as_class() else {
+                    unreachable!()
+                };
+                // Inside a method, `this` is always bound somewhere inside the environment
+                // holding `super`.
+                let this = environment.borrow().get("this").unwrap();
+                let method = superclass.find_method(method.lexeme()).ok_or_else(|| {
+                    LoxError::from_token(
+                        &method,
+                        format!("Undefined property '{}'.", method.lexeme()),
+                    )
+                    .with_code(Code::UndefinedProperty)
+                })?;
+                Ok(Literal::function(method.bind(this)))
+            }
+            Expr::This { ref keyword, depth } => self.look_up_variable(keyword, depth, environment),
+        }
+    }
+
+    /// Record the start of a call to a Lox function.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if calls are already nested as deeply as the VM allows.
+    pub(crate) fn push_frame(&mut self, frame: Frame) -> Result<(), LoxError> {
+        // The VM counts the script as a frame too.
+        if self.frames.len() + 1 == FRAMES_MAX {
+            return Err(
+                LoxError::new(frame.line(), frame.col(), "Stack overflow.".to_string())
+                    .with_code(Code::StackOverflow),
+            );
+        }
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Record that the innermost call returned.
+    pub(crate) fn pop_frame(&mut self) {
+        self.frames.pop();
+    }
+
+    /// Look up a variable at the depth the resolver found for it, or in the globals if it
+    /// resolved to none.
+    fn look_up_variable(
+        &self,
+        name: &Token,
+        depth: Depth,
+        environment: &EnvironmentRef,
+    ) -> Result<Literal, LoxError> {
+        match depth {
+            Some(distance) => environment.borrow().get_at(distance, name),
+            None => self.globals.borrow().get_var(name),
         }
     }
 
     fn execute(
         &mut self,
         statement: Stmt,
-        environment: &mut Environment,
-    ) -> Result<Literal, LoxError> {
+        environment: &EnvironmentRef,
+    ) -> Result<ControlFlow, LoxError> {
         match statement {
             Stmt::Block { statements } => {
-                self.execute_block(statements, environment)?;
-                Ok(Literal::Nil)
+                self.execute_block(statements, Environment::from_parent(environment).shared())
+            }
+            class @ Stmt::Class { .. } => {
+                let superclass = match class {
+                    Stmt::Class {
+                        superclass: Some(ref superclass),
+                        ..
+                    } => {
+                        let Expr::Variable { ref name, .. } = superclass else {
+                            unreachable!()
+                        };
+                        match self.evaluate(superclass.clone(), environment)?.as_class() {
+                            Some(superclass) => Some(Rc::clone(superclass)),
+                            None => {
+                                return Err(LoxError::from_token(
+                                    name,
+                                    "Superclass must be a class.".to_string(),
+                                )
+                                .with_code(Code::SuperclassNotClass))
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                // Methods of a subclass close over an extra environment in which `super` is bound.
+                let closure = match superclass {
+                    Some(ref superclass) => {
+                        let mut closure = Environment::from_parent(environment);
+                        closure.define("super".to_string(), Literal::class(Rc::clone(superclass)));
+                        closure.shared()
+                    }
+                    None => Rc::clone(environment),
+                };
+                let class = Class::new(class, superclass, closure).unwrap();
+                environment
+                    .borrow_mut()
+                    .define(class.name().lexeme().to_string(), Literal::class(class));
+
+                Ok(ControlFlow::Normal)
+            }
+            Stmt::Break { .. } => Ok(ControlFlow::Break),
+            Stmt::Continue { .. } => Ok(ControlFlow::Continue),
+            Stmt::Expression { expression } => {
+                self.evaluate(expression, environment)?;
+                Ok(ControlFlow::Normal)
             }
-            Stmt::Expression { expression } => self.evaluate(expression, environment),
             function @ Stmt::Function { .. } => {
-                let function = Function::new(function).unwrap();
-                environment.define(
+                let function = Function::new(function, Rc::clone(environment)).unwrap();
+                environment.borrow_mut().define(
                     function.name().lexeme().to_string(),
-                    Literal::Fun(Box::new(function)),
+                    Literal::function(function),
                 );
 
-                Ok(Literal::Nil)
+                Ok(ControlFlow::Normal)
             }
             Stmt::If {
                 condition,
                 then_branch,
                 else_branch,
             } => {
-                // NOTE: I stray from the book here, because I just really, really like expression
-                // based languages. If, in this implementation, returns the result literal from
-                // the executed branch.
+                // NOTE: The control flow of the executed branch is passed on as is, so that a
+                // `return` or `break` inside of it reaches the enclosing function or loop.
                 if self.evaluate(condition, environment)?.is_truthy() {
                     self.execute(*then_branch, environment)
                 } else if let Some(else_branch) = else_branch {
                     self.execute(*else_branch, environment)
                 } else {
-                    Ok(Literal::Nil)
+                    Ok(ControlFlow::Normal)
                 }
             }
             Stmt::Print { expression } => {
-                println!("{}", self.evaluate(expression, environment)?);
-                Ok(Literal::Nil)
+                let value = self.evaluate(expression, environment)?;
+                writeln!(self.output, "{value}").map_err(|e| {
+                    LoxError::without_location(format!("Could not write output: {e}"))
+                })?;
+                Ok(ControlFlow::Normal)
             }
-            Stmt::Return { keyword, value } => {
+            Stmt::Return { value, .. } => {
                 let value = match value {
                     Some(val) => self.evaluate(val, environment)?,
                     None => Literal::Nil,
                 };
-                self.return_value = Some(value.clone());
-                Err(LoxError::return_unwind(&keyword))
+                Ok(ControlFlow::Return(value))
             }
             Stmt::Var { name, initializer } => {
                 let value = if let Some(init) = initializer {
@@ -251,48 +460,92 @@ impl Interpreter {
                 } else {
                     Literal::Nil
                 };
-                environment.define(name.lexeme().to_string(), value);
-                Ok(Literal::Nil)
+                environment
+                    .borrow_mut()
+                    .define(name.lexeme().to_string(), value);
+                Ok(ControlFlow::Normal)
             }
-            Stmt::While { condition, body } => {
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
                 // TODO: These clones might actually give us undesirable and incorrect behaviour.
                 while self.evaluate(condition.clone(), environment)?.is_truthy() {
-                    self.execute(*body.clone(), environment)?;
+                    match self.execute(*body.clone(), environment)? {
+                        ControlFlow::Break => break,
+                        // The increment below still has to run after a `continue`.
+                        ControlFlow::Normal | ControlFlow::Continue => {}
+                        flow @ ControlFlow::Return(_) => return Ok(flow),
+                    }
+                    if let Some(ref increment) = increment {
+                        self.evaluate(increment.clone(), environment)?;
+                    }
                 }
-                Ok(Literal::Nil)
+                Ok(ControlFlow::Normal)
             }
         }
     }
 
+    /// Execute `statements` in `environment`, which is usually a fresh environment enclosing
+    /// the one the block appears in.
+    ///
+    /// Execution stops early at the first statement that does not complete normally, and its
+    /// control flow is passed on to the caller.
     pub(crate) fn execute_block(
         &mut self,
         statements: Vec<Stmt>,
-        environment: &mut Environment,
-    ) -> Result<(), LoxError> {
-        let mut block_env = Environment::from_parent(environment);
+        environment: EnvironmentRef,
+    ) -> Result<ControlFlow, LoxError> {
         for statement in statements {
-            self.execute(statement, &mut block_env)?;
+            match self.execute(statement, &environment)? {
+                ControlFlow::Normal => {}
+                flow => return Ok(flow),
+            }
         }
-        // std::mem::swap(&mut *block_env.fallback.unwrap(), environment);
-        *environment = std::mem::take(&mut block_env.fallback()).unwrap();
-        Ok(())
+        Ok(ControlFlow::Normal)
     }
 
-    pub(crate) fn interpret(&mut self, statements: Vec<Stmt>) -> Result<String, LoxError> {
-        let mut environment = Environment::new();
-        self.interpret_with_env(statements, &mut environment)
+    pub(crate) fn globals(&self) -> &EnvironmentRef {
+        &self.globals
     }
 
-    pub(crate) fn interpret_with_env(
-        &mut self,
-        statements: Vec<Stmt>,
-        environment: &mut Environment,
-    ) -> Result<String, LoxError> {
+    /// Run `statements` as a script in the global scope of this interpreter, and return the value
+    /// of the expression statement it ends with, or nil if it ends with another kind of statement.
+    pub(crate) fn interpret(&mut self, statements: Vec<Stmt>) -> Result<Literal, LoxError> {
+        let environment = Rc::clone(&self.globals);
+        let mut result = Literal::Nil;
         for statement in statements {
-            self.execute(statement, environment)?;
+            result = Literal::Nil;
+            let flow = match statement {
+                Stmt::Expression { expression } => {
+                    self.evaluate(expression, &environment).map(|value| {
+                        result = value;
+                        ControlFlow::Normal
+                    })
+                }
+                statement => self.execute(statement, &environment),
+            };
+            let flow = flow.map_err(|error| {
+                let trace = self.frames.drain(..).rev().collect();
+                error.with_trace(trace)
+            })?;
+            // The resolver rejects `return` outside of a function, but should one get through
+            // anyway, it ends the script.
+            if let ControlFlow::Return(_) = flow {
+                break;
+            }
         }
 
-        // TODO this is wrong of course. (temp)
-        Ok(String::new())
+        Ok(result)
+    }
+}
+
+impl Debug for Interpreter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("globals", &self.globals)
+            .field("frames", &self.frames)
+            .finish_non_exhaustive()
     }
 }
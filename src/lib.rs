@@ -0,0 +1,774 @@
+//! An interpreter for the Lox programming language.
+//!
+//! The [`Lox`] type is the entry point for embedding rlox in a Rust program. It holds on to a
+//! single interpreter session, so globals defined by one call to [`Lox::eval`] are visible to the
+//! next, as if all the code run in the session were one file. Programs either run on a tree-walking
+//! interpreter, or are compiled to bytecode for a virtual machine; see [`Backend`].
+
+mod ast;
+mod callable;
+mod chunk;
+mod class;
+mod compiler;
+mod diagnostic;
+mod disassembler;
+mod environment;
+mod interpreter;
+mod loxc;
+mod map;
+mod native;
+mod output;
+mod parser;
+mod resolver;
+mod scanner;
+mod token;
+mod vm;
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{read, File};
+use std::io::{stderr, Read, Write};
+use std::path::Path;
+
+use ast::Stmt;
+use callable::NativeFunction;
+use compiler::Compiler;
+use diagnostic::Code;
+use environment::EnvironmentRef;
+use interpreter::Interpreter;
+use parser::Parser;
+use resolver::Resolver;
+use scanner::Scanner;
+use token::{Token, TokenType};
+use vm::Vm;
+
+pub use output::SharedBuffer;
+pub use token::{Literal, LoxClass, LoxFunction, LoxInstance};
+
+#[derive(Debug, Clone)]
+pub struct LoxError {
+    line: usize,
+    /// The column of the first character of the code the error is about.
+    col: usize,
+    /// The column of the last character of the code the error is about.
+    end_col: usize,
+    // Boxed rather than growable, to keep the many `Result`s carrying errors small.
+    place: Box<str>, // where
+    message: String,
+    code: Option<Code>,
+    notes: Box<[String]>,
+    trace: Box<[Frame]>,
+    runtime: bool,
+    /// Errors reported together with this one, which occur later in the source.
+    others: Box<[LoxError]>,
+}
+
+impl LoxError {
+    pub(crate) fn new(line: usize, col: usize, message: String) -> Self {
+        Self::with_place(line, col, String::new(), message)
+    }
+
+    fn with_place(line: usize, col: usize, place: String, message: String) -> Self {
+        Self {
+            line,
+            col,
+            end_col: col,
+            place: place.into(),
+            message,
+            code: None,
+            notes: Box::default(),
+            trace: Box::default(),
+            runtime: false,
+            others: Box::default(),
+        }
+    }
+
+    /// Extend the span of this error to end at `end_col`, on the same line.
+    pub(crate) fn with_end_col(self, end_col: usize) -> Self {
+        Self {
+            end_col: end_col.max(self.col),
+            ..self
+        }
+    }
+
+    /// Give this error the code of its kind.
+    pub(crate) fn with_code(self, code: Code) -> Self {
+        Self {
+            code: Some(code),
+            ..self
+        }
+    }
+
+    /// Add a note with more information about this error.
+    pub(crate) fn with_note(self, note: String) -> Self {
+        let mut notes = self.notes.into_vec();
+        notes.push(note);
+        Self {
+            notes: notes.into_boxed_slice(),
+            ..self
+        }
+    }
+
+    /// Combine several errors into one, whose other errors follow it in order of position.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `errors` is empty.
+    pub(crate) fn from_many(mut errors: Vec<LoxError>) -> Self {
+        errors.sort_by_key(|error| (error.line, error.col));
+        let mut errors = errors.into_iter();
+        let first = errors.next().expect("there is at least one error");
+        Self {
+            others: errors.collect(),
+            ..first
+        }
+    }
+
+    /// Mark this error as having occurred while the program ran, and attach the calls that were
+    /// in progress when it did, innermost first.
+    pub(crate) fn with_trace(self, trace: Vec<Frame>) -> Self {
+        Self {
+            trace: trace.into_boxed_slice(),
+            runtime: true,
+            ..self
+        }
+    }
+
+    pub(crate) fn from_token(token: &Token, message: String) -> Self {
+        let error = match token.token_type() {
+            TokenType::Eof => {
+                Self::with_place(token.line(), token.col(), "at end".to_string(), message)
+            }
+            _ => Self::with_place(
+                token.line(),
+                token.col(),
+                format!("at '{}'", token.lexeme()),
+                message,
+            ),
+        };
+        error.with_end_col(token.end_col())
+    }
+
+    /// Whether this error was found at the end of the source.
+    fn is_at_end(&self) -> bool {
+        &*self.place == "at end"
+    }
+
+    /// An error that is not tied to a place in the source, such as failing to read a file.
+    pub(crate) fn without_location(message: String) -> Self {
+        Self::new(0, 0, message)
+    }
+
+    pub(crate) fn unexpected_type(token: &Token) -> LoxError {
+        LoxError::from_token(token, format!("Unexpected type of token {token}"))
+            .with_code(Code::UnexpectedType)
+    }
+
+    /// The 1-indexed line the error occurred at, or 0 if the error has no location.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The 1-indexed column the error starts at.
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    /// The 1-indexed column the error ends at, inclusive. It is on the same line as the start.
+    pub fn end_col(&self) -> usize {
+        self.end_col
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The stable code of the kind of this error, such as `E0001` for "Expect expression.", if
+    /// it has one. Errors raised by host functions have none.
+    pub fn code(&self) -> Option<&'static str> {
+        self.code.map(Code::as_str)
+    }
+
+    /// Further information about this error, in addition to its message.
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    /// Render this error and the errors reported with it for people to read, quoting the line
+    /// of `source` each one occurred at and underlining where.
+    ///
+    /// `source` should be the source code the errors were found in. Lines it does not have are
+    /// not quoted.
+    pub fn render(&self, source: &str) -> String {
+        diagnostic::render(self, source, 1)
+    }
+
+    /// Write this error and the errors reported with it for tools to read, as one JSON object
+    /// per line. `file` is the path of the script the errors occurred in, if any.
+    ///
+    /// Each object has the `file`, `line`, `col`, `span`, `severity`, `code`, `message`, `notes`
+    /// and `frames` of an error; the frames are its [`LoxError::trace`].
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        diagnostic::json(self, file)
+    }
+
+    /// Write this error and the errors reported with it in `error_format`: rendered against
+    /// `source` with [`LoxError::render`], or as JSON from `file` with [`LoxError::to_json`].
+    pub fn format(&self, error_format: ErrorFormat, source: &str, file: Option<&str>) -> String {
+        self.format_from_line(error_format, source, 1, file)
+    }
+
+    /// Like [`LoxError::format`], for a `source` whose first line is line `first_line`.
+    fn format_from_line(
+        &self,
+        error_format: ErrorFormat,
+        source: &str,
+        first_line: usize,
+        file: Option<&str>,
+    ) -> String {
+        match error_format {
+            ErrorFormat::Human => diagnostic::render(self, source, first_line),
+            ErrorFormat::Json => self.to_json(file),
+        }
+    }
+
+    /// Whether this error occurred while the program ran, rather than before it could, or
+    /// outside of it, such as when a file could not be read.
+    pub fn is_runtime(&self) -> bool {
+        self.runtime
+    }
+
+    /// The function calls that were in progress when this runtime error occurred, innermost
+    /// first. The script that made the outermost call is not included, so the trace is empty
+    /// for errors outside of any function, and for errors that occur before the program runs.
+    pub fn trace(&self) -> &[Frame] {
+        &self.trace
+    }
+
+    /// The errors reported together with this one, which occur later in the source. Scanning
+    /// and parsing report every error they find, rather than only the first one.
+    ///
+    /// The `Display` implementation shows this error followed by all of the others.
+    pub fn others(&self) -> &[LoxError] {
+        &self.others
+    }
+}
+
+impl Error for LoxError {}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            line,
+            col,
+            place,
+            message,
+            trace,
+            others,
+            ..
+        } = self;
+        if *line == 0 {
+            return write!(f, "Error: {message}");
+        }
+        write!(f, "[line {line}, col {col}] Error {place}: {message}")?;
+
+        if !trace.is_empty() {
+            writeln!(f)?;
+            diagnostic::write_trace(f, trace, "    ")?;
+        }
+
+        for other in others {
+            write!(f, "\n{other}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A runtime error raised away from the code that caused it, such as inside of a native
+/// function, which becomes a [`LoxError`] once it is given the place it is reported at.
+#[derive(Debug, Clone)]
+pub(crate) struct RuntimeError {
+    code: Option<Code>,
+    message: String,
+}
+
+impl RuntimeError {
+    pub(crate) fn new(code: Code, message: String) -> Self {
+        Self {
+            code: Some(code),
+            message,
+        }
+    }
+
+    /// Turn this into a [`LoxError`], located by `error` from the message.
+    pub(crate) fn located(self, error: impl FnOnce(String) -> LoxError) -> LoxError {
+        LoxError {
+            code: self.code,
+            ..error(self.message)
+        }
+    }
+}
+
+/// An error without a code, such as one returned by a host function.
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        Self {
+            code: None,
+            message,
+        }
+    }
+}
+
+/// A function call that was in progress when a runtime error occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    function: String,
+    line: usize,
+    col: usize,
+}
+
+impl Frame {
+    pub(crate) fn new(function: &str, line: usize, col: usize) -> Self {
+        Self {
+            function: function.to_string(),
+            line,
+            col,
+        }
+    }
+
+    /// The name of the called function, or the empty string for an anonymous function.
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    /// The line of the call.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The column of the closing parenthesis of the call.
+    pub fn col(&self) -> usize {
+        self.col
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.function.as_str() {
+            "" => write!(f, "at <fn> (line {})", self.line),
+            function => write!(f, "at {function} (line {})", self.line),
+        }
+    }
+}
+
+/// Scan, parse and resolve `source`, whose lines are numbered from `first_line`.
+///
+/// All errors from scanning and parsing are reported together. The resolver only runs on a
+/// program without syntax errors, and stops at its first error.
+fn parse(source: &str, first_line: usize) -> Result<Vec<Stmt>, LoxError> {
+    let scanner = Scanner::new(source).with_first_line(first_line);
+    let (tokens, mut errors) = scanner.scan_tokens();
+
+    let parser = Parser::new(tokens);
+    let parsed = parser.parse();
+    if let Err(parse_errors) = &parsed {
+        errors.extend_from_slice(parse_errors);
+    }
+    if !errors.is_empty() {
+        return Err(LoxError::from_many(errors));
+    }
+    let mut parsed = parsed.unwrap_or_default();
+
+    let resolver = Resolver::new();
+    resolver.resolve(&mut parsed)?;
+
+    Ok(parsed)
+}
+
+/// Whether the file at `path` holds compiled bytecode, which [`Lox::run_file`] runs as such: it
+/// has the `.loxc` extension, or starts with the magic bytes of one. Files that can't be read
+/// are not bytecode.
+pub fn is_bytecode_file(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "loxc")
+    {
+        return true;
+    }
+    let mut start = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(loxc::MAGIC.len() as u64).read_to_end(&mut start))
+        .is_ok_and(|_| loxc::is_bytecode(&start))
+}
+
+/// The reserved words of Lox, which can't be used as names.
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    scanner::KEYWORDS.iter().map(|&(keyword, _)| keyword)
+}
+
+/// Scan `source`, and return a listing of its tokens, one per line, each with the line and
+/// column it starts at.
+///
+/// # Errors
+///
+/// This function will return an error if the source fails to scan.
+pub fn token_listing(source: &str) -> Result<String, LoxError> {
+    let (tokens, errors) = Scanner::new(source).scan_tokens();
+    if !errors.is_empty() {
+        return Err(LoxError::from_many(errors));
+    }
+    Ok(tokens
+        .iter()
+        .map(|token| format!("{}:{} {token}\n", token.line(), token.col()))
+        .collect())
+}
+
+/// Parse `source` as a single expression, and return its syntax tree, written out in full.
+///
+/// # Errors
+///
+/// This function will return an error if the source fails to scan, or is not one expression.
+pub fn expression_tree(source: &str) -> Result<String, LoxError> {
+    let (tokens, errors) = Scanner::new(source).scan_tokens();
+    if !errors.is_empty() {
+        return Err(LoxError::from_many(errors));
+    }
+    Ok(Parser::new(tokens).parse_expression()?.to_string())
+}
+
+/// Whether `source` is the start of a program that is not finished yet, so that more lines
+/// could complete it: it has a string, or a bracket, brace or parenthesis, that is not closed,
+/// or the first error in it is that it ends too soon.
+///
+/// Interactive prompts use this to keep reading a statement typed over several lines.
+pub fn is_incomplete(source: &str) -> bool {
+    let (tokens, errors) = Scanner::new(source).scan_tokens();
+    if let Some(error) = errors.first() {
+        return error.code == Some(Code::UnterminatedString);
+    }
+
+    let depth: isize = tokens
+        .iter()
+        .map(|token| match token.token_type() {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => -1,
+            _ => 0,
+        })
+        .sum();
+    if depth != 0 {
+        // Too many closing brackets can't be fixed by adding more code.
+        return depth > 0;
+    }
+
+    match Parser::new(tokens).parse() {
+        Ok(_) => false,
+        // More code can't fix an error before the end, whatever comes after it.
+        Err(errors) => errors.first().is_some_and(LoxError::is_at_end),
+    }
+}
+
+/// Compile `source` to bytecode, and return a listing of the instructions of the script and of
+/// every function in it, as run by [`Backend::Vm`].
+///
+/// # Errors
+///
+/// This function will return an error if the source fails to scan, parse, resolve or compile.
+pub fn disassemble(source: &str) -> Result<String, LoxError> {
+    let script = Compiler::new().compile(&parse(source, 1)?)?;
+    Ok(disassembler::disassemble(&script))
+}
+
+/// Compile `source` to bytecode, and return it encoded as the contents of a `.loxc` file.
+///
+/// The file can be run with [`Lox::run_file`] or [`Lox::run_bytecode`], on [`Backend::Vm`],
+/// without scanning and parsing the source again.
+///
+/// # Errors
+///
+/// This function will return an error if the source fails to scan, parse, resolve or compile.
+pub fn compile(source: &str) -> Result<Vec<u8>, LoxError> {
+    let script = Compiler::new().compile(&parse(source, 1)?)?;
+    Ok(loxc::encode(&script))
+}
+
+/// The implementation that runs the programs of a [`Lox`] session.
+///
+/// Both backends run the same programs with the same results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Run the syntax tree directly.
+    #[default]
+    TreeWalker,
+    /// Compile the syntax tree to bytecode, and run that on a stack-based virtual machine.
+    Vm,
+}
+
+/// How a [`Lox`] session reports errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorFormat {
+    /// For people: each error quotes the line it occurred at; see [`LoxError::render`].
+    #[default]
+    Human,
+    /// For tools: one JSON object per error, per line; see [`LoxError::to_json`].
+    Json,
+}
+
+#[derive(Debug)]
+enum Engine {
+    TreeWalker(Interpreter),
+    Vm(Vm),
+}
+
+/// How many bytes of the code run earlier in a [`Lox`] session are kept, to quote the lines of
+/// errors in it. Errors on lines that have been forgotten are reported without a quote.
+const TRANSCRIPT_LIMIT: usize = 1 << 20;
+
+/// An rlox interpreter session.
+pub struct Lox {
+    engine: Engine,
+    /// Where errors and other diagnostics are reported to.
+    diagnostics: Box<dyn Write>,
+    /// The source code run in this session, one piece after another. Its lines are numbered as if
+    /// it were one file, so that errors in functions defined by code run earlier are quoted from
+    /// the right line. Only the last [`TRANSCRIPT_LIMIT`] bytes of earlier code are kept.
+    transcript: String,
+    /// The number of the first line still in `transcript`.
+    first_line: usize,
+    /// The number of lines of code run in this session.
+    lines: usize,
+    /// Whether the code run most recently was bytecode, whose source is not at hand.
+    ran_bytecode: bool,
+    /// The path of the file run most recently, if the code run most recently came from one,
+    /// and its lines are numbered as in the file.
+    file: Option<String>,
+    error_format: ErrorFormat,
+}
+
+impl Lox {
+    /// Create a new session on the tree-walking backend, with only the standard prelude defined.
+    pub fn new() -> Self {
+        Self::with_backend(Backend::default())
+    }
+
+    /// Create a new session on `backend`, with only the standard prelude defined.
+    pub fn with_backend(backend: Backend) -> Self {
+        let engine = match backend {
+            Backend::TreeWalker => Engine::TreeWalker(Interpreter::new()),
+            Backend::Vm => Engine::Vm(Vm::new()),
+        };
+        Self {
+            engine,
+            diagnostics: Box::new(stderr()),
+            transcript: String::new(),
+            first_line: 1,
+            lines: 0,
+            ran_bytecode: false,
+            file: None,
+            error_format: ErrorFormat::default(),
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self.engine {
+            Engine::TreeWalker(_) => Backend::TreeWalker,
+            Engine::Vm(_) => Backend::Vm,
+        }
+    }
+
+    /// Run `source` in this session, and return the value of the expression statement it ends
+    /// with, such as `3` for `var a = 1; a + 2;`. Sources ending with another kind of statement
+    /// return nil.
+    ///
+    /// The lines of `source` are numbered on from those of the code run before it in this
+    /// session, so a session behaves just like a file holding all of the code run in it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the source fails to scan, parse or resolve, or if a
+    /// runtime error occurs while running it.
+    pub fn eval(&mut self, source: &str) -> Result<Literal, LoxError> {
+        self.ran_bytecode = false;
+        self.file = None;
+        let first_line = self.lines + 1;
+        self.forget_old_lines();
+        self.transcript.push_str(source);
+        if !source.ends_with('\n') {
+            self.transcript.push('\n');
+        }
+        self.lines += source.lines().count().max(1);
+
+        let parsed = parse(source, first_line)?;
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => interpreter.interpret(parsed),
+            Engine::Vm(vm) => {
+                let script = Compiler::new().compile(&parsed)?;
+                vm.interpret(script)
+            }
+        }
+    }
+
+    /// Drop lines from the start of the transcript, until it is no longer than
+    /// [`TRANSCRIPT_LIMIT`].
+    fn forget_old_lines(&mut self) {
+        if self.transcript.len() <= TRANSCRIPT_LIMIT {
+            return;
+        }
+        let excess = self.transcript.len() - TRANSCRIPT_LIMIT;
+        // The transcript ends with a newline, so there is one at or after any excess.
+        let end = excess
+            + self.transcript.as_bytes()[excess..]
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .expect("the transcript ends with a newline");
+        self.first_line += self.transcript[..=end].matches('\n').count();
+        self.transcript.drain(..=end);
+    }
+
+    /// Run the contents of a `.loxc` file, as produced by [`compile`], in this session.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the session does not run on [`Backend::Vm`], if
+    /// the bytecode is truncated or corrupt, or if a runtime error occurs while running it.
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> Result<(), LoxError> {
+        let Engine::Vm(vm) = &mut self.engine else {
+            return Err(LoxError::without_location(
+                "Compiled bytecode can only run on the VM backend.".to_string(),
+            ));
+        };
+        self.ran_bytecode = true;
+        self.file = None;
+        let script = loxc::decode(bytes)?;
+        vm.interpret(script)?;
+        Ok(())
+    }
+
+    /// Read the script at `path` and run it in this session.
+    ///
+    /// Files with the `.loxc` extension, or starting with its magic bytes, are run as compiled
+    /// bytecode. Anything else is run as source code.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be read, or under the same
+    /// conditions as [`Lox::eval`] or [`Lox::run_bytecode`].
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<(), LoxError> {
+        let path = path.as_ref();
+        // Only the first code run in a session has the same line numbers as in its file.
+        let first = self.lines == 0;
+        let result = self.run_path(path);
+        self.file = first.then(|| path.display().to_string());
+        result
+    }
+
+    fn run_path(&mut self, path: &Path) -> Result<(), LoxError> {
+        let bytes = read(path).map_err(|e| {
+            LoxError::without_location(format!("Could not read '{}': {e}", path.display()))
+        })?;
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "loxc")
+            || loxc::is_bytecode(&bytes)
+        {
+            return self.run_bytecode(&bytes);
+        }
+
+        let source = String::from_utf8(bytes).map_err(|_| {
+            LoxError::without_location(format!("'{}' is not valid UTF-8.", path.display()))
+        })?;
+        self.eval(&source)?;
+        Ok(())
+    }
+
+    /// Send the output of `print` statements to `output`, instead of to standard output.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => interpreter.set_output(Box::new(output)),
+            Engine::Vm(vm) => vm.set_output(Box::new(output)),
+        }
+    }
+
+    /// Send diagnostics to `diagnostics`, instead of to standard error.
+    pub fn set_diagnostics(&mut self, diagnostics: impl Write + 'static) {
+        self.diagnostics = Box::new(diagnostics);
+    }
+
+    /// Report errors in `error_format`, instead of for people to read.
+    pub fn set_error_format(&mut self, error_format: ErrorFormat) {
+        self.error_format = error_format;
+    }
+
+    /// Report `error` to the diagnostics of this session, in its error format. Errors from
+    /// source code run in this session quote the line they occurred at, and errors from a file
+    /// run first in the session name the file.
+    pub fn report(&mut self, error: &LoxError) {
+        let source = if self.ran_bytecode {
+            ""
+        } else {
+            &self.transcript
+        };
+        let report = error.format_from_line(
+            self.error_format,
+            source,
+            self.first_line,
+            self.file.as_deref(),
+        );
+        // If even the diagnostics cannot be written, there is nowhere left to complain to.
+        let _ = write!(self.diagnostics, "{report}");
+    }
+
+    /// Define a global variable, or overwrite it if it already exists.
+    pub fn set_global(&mut self, name: &str, value: Literal) {
+        self.globals().borrow_mut().define(name.to_string(), value);
+    }
+
+    /// Get the value of a global variable, if it is defined.
+    pub fn get_global(&self, name: &str) -> Option<Literal> {
+        self.globals().borrow().get(name)
+    }
+
+    /// The global variables of this session, including the standard prelude, with their values,
+    /// sorted by name.
+    pub fn global_bindings(&self) -> Vec<(String, Literal)> {
+        self.globals().borrow().bindings()
+    }
+
+    /// Define a global function named `name` that calls `function` on the host.
+    ///
+    /// Scripts must call the function with exactly `arity` arguments. The arguments can be
+    /// converted with `TryFrom<Literal>` for the basic Rust types, whose error messages can be
+    /// passed on as is: returning `Err(message)` raises a runtime error located at the call.
+    pub fn define_fn<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(Vec<Literal>) -> Result<Literal, String> + 'static,
+    {
+        self.globals()
+            .borrow_mut()
+            .define_native(NativeFunction::new(name, arity, move |arguments| {
+                function(arguments).map_err(RuntimeError::from)
+            }));
+    }
+
+    fn globals(&self) -> &EnvironmentRef {
+        match &self.engine {
+            Engine::TreeWalker(interpreter) => interpreter.globals(),
+            Engine::Vm(vm) => vm.globals(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Lox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lox")
+            .field("engine", &self.engine)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,503 @@
+//! The `.loxc` file format, for compiled scripts.
+//!
+//! A file starts with a header: the magic bytes `LOXC`, the format version as a `u16`, and an
+//! FNV-1a hash of the rest of the file as a `u64`. The rest is the prototype of the script.
+//!
+//! A prototype is written as its name, arity and upvalue count, followed by its chunk: the
+//! bytecode, the constant pool and the line table. Functions in the constant pool are written as
+//! nested prototypes. All integers are big-endian, and lengths and counts are `u32`s. Strings are
+//! written as their length in bytes, followed by their UTF-8 encoding.
+//!
+//! Loading a file checks it thoroughly enough to turn truncation and corruption into errors,
+//! even for a file that was crafted by hand, checksum and all. Besides the operands of each
+//! instruction, it follows every path through each function to check that the stack never
+//! underflows, that locals are only read from inside of the frame, and that captured locals are
+//! closed before they leave it, as the VM assumes.
+
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Constant, LineStart, OpCode, Prototype};
+use crate::LoxError;
+
+/// The magic bytes every `.loxc` file starts with.
+pub(crate) const MAGIC: &[u8; 4] = b"LOXC";
+
+/// The version of the format written by this build. Files of any other version are rejected,
+/// because the instruction set may have changed in between.
+const VERSION: u16 = 4;
+
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+/// How deeply functions may be nested in a file. The compiler has no such limit, but it keeps a
+/// malicious file from exhausting the stack of the loader.
+const MAX_NESTING: usize = 256;
+
+/// Encode the compiled `script` as the contents of a `.loxc` file.
+pub(crate) fn encode(script: &Prototype) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_prototype(&mut payload, script);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    bytes.extend_from_slice(&fnv1a(&payload).to_be_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Decode the contents of a `.loxc` file back into the prototype of the script.
+///
+/// # Errors
+///
+/// This function will return an error if the file is not a `.loxc` file of the version this
+/// build writes, or if it is truncated or corrupt.
+pub(crate) fn decode(bytes: &[u8]) -> Result<Rc<Prototype>, LoxError> {
+    let mut reader = Reader { bytes, position: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(corrupt(0, "not a compiled Lox file"));
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(LoxError::without_location(format!(
+            "Unsupported bytecode version {version}, expected version {VERSION}."
+        )));
+    }
+    let checksum = reader.u64()?;
+    if fnv1a(&bytes[HEADER_LEN..]) != checksum {
+        return Err(corrupt(HEADER_LEN, "checksum mismatch"));
+    }
+
+    let script = reader.prototype(0)?;
+    if reader.position != bytes.len() {
+        return Err(corrupt(reader.position, "unexpected data after the script"));
+    }
+    Ok(Rc::new(script))
+}
+
+/// Whether `bytes` look like the contents of a `.loxc` file, rather than source code.
+pub(crate) fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn write_prototype(out: &mut Vec<u8>, prototype: &Prototype) {
+    write_str(out, &prototype.name);
+    write_len(out, prototype.arity);
+    write_len(out, prototype.upvalue_count);
+
+    let chunk = &prototype.chunk;
+    write_len(out, chunk.code().len());
+    out.extend_from_slice(chunk.code());
+
+    write_len(out, chunk.constants().len());
+    for constant in chunk.constants() {
+        match constant {
+            Constant::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_bits().to_be_bytes());
+            }
+            Constant::String(s) => {
+                out.push(TAG_STRING);
+                write_str(out, s);
+            }
+            Constant::Function(function) => {
+                out.push(TAG_FUNCTION);
+                write_prototype(out, function);
+            }
+        }
+    }
+
+    write_len(out, chunk.lines().len());
+    for start in chunk.lines() {
+        write_len(out, start.offset);
+        write_len(out, start.line);
+        write_len(out, start.col);
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    // Nothing the compiler produces comes close to four billion of anything.
+    let len = u32::try_from(len).expect("length fits in a u32");
+    out.extend_from_slice(&len.to_be_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Reads the parts of a `.loxc` file, checking every read against the end of the file.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LoxError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| corrupt(self.position, "unexpected end of file"))?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoxError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoxError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoxError> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buffer))
+    }
+
+    fn len(&mut self) -> Result<usize, LoxError> {
+        let mut buffer = [0; 4];
+        buffer.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buffer) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, LoxError> {
+        let start = self.position;
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| corrupt(start, "invalid UTF-8 in string"))
+    }
+
+    fn prototype(&mut self, nesting: usize) -> Result<Prototype, LoxError> {
+        if nesting > MAX_NESTING {
+            return Err(corrupt(self.position, "functions are nested too deeply"));
+        }
+
+        let start = self.position;
+        let name = self.string()?;
+        let arity = self.len()?;
+        let upvalue_count = self.len()?;
+
+        let code_len = self.len()?;
+        let code = self.take(code_len)?.to_vec();
+
+        // Don't trust the counts below to preallocate: they might be corrupt.
+        let mut constants = Vec::new();
+        for _ in 0..self.len()? {
+            let position = self.position;
+            let constant = match self.u8()? {
+                TAG_NUMBER => Constant::Number(f64::from_bits(self.u64()?)),
+                TAG_STRING => Constant::String(self.string()?.into()),
+                TAG_FUNCTION => Constant::Function(Rc::new(self.prototype(nesting + 1)?)),
+                tag => return Err(corrupt(position, &format!("unknown constant tag {tag}"))),
+            };
+            constants.push(constant);
+        }
+
+        let mut lines = Vec::new();
+        for _ in 0..self.len()? {
+            let position = self.position;
+            let line = LineStart {
+                offset: self.len()?,
+                line: self.len()?,
+                col: self.len()?,
+            };
+            if lines
+                .last()
+                .is_some_and(|last: &LineStart| last.offset >= line.offset)
+            {
+                return Err(corrupt(position, "line table is out of order"));
+            }
+            lines.push(line);
+        }
+
+        let prototype = Prototype {
+            name,
+            arity,
+            upvalue_count,
+            chunk: Chunk::from_parts(code, constants, lines),
+        };
+        verify(&prototype, nesting == 0).map_err(|message| corrupt(start, &message))?;
+        Ok(prototype)
+    }
+}
+
+/// Check that every instruction of `prototype` is complete, and refers to constants, upvalues
+/// and code that exist. This catches the damage a file could have suffered on the way; the
+/// checksum already makes it unlikely.
+fn verify(prototype: &Prototype, is_script: bool) -> Result<(), String> {
+    let current = if is_script {
+        "<script>".to_string()
+    } else {
+        prototype.to_string()
+    };
+    let code = prototype.chunk.code();
+    let constants = prototype.chunk.constants();
+    let operand = |at: usize| {
+        code.get(at)
+            .copied()
+            .ok_or_else(|| format!("truncated instruction in {current}"))
+    };
+    let short = |at: usize| -> Result<usize, String> {
+        Ok(u16::from_be_bytes([operand(at)?, operand(at + 1)?]) as usize)
+    };
+    let constant = |at: usize| -> Result<&Constant, String> {
+        let index = short(at)?;
+        constants
+            .get(index)
+            .ok_or_else(|| format!("missing constant {index} in {current}"))
+    };
+    let name = |at: usize| match constant(at)? {
+        Constant::String(_) => Ok(()),
+        _ => Err(format!("expected a name constant in {current}")),
+    };
+
+    // The VM calls the script with no arguments, and makes no closure for it to capture
+    // variables with, so it can't have parameters or upvalues to read.
+    if is_script && prototype.arity != 0 {
+        return Err(format!("{current} has parameters"));
+    }
+    if is_script && prototype.upvalue_count != 0 {
+        return Err(format!("{current} has upvalues"));
+    }
+    if code.last() != Some(&(OpCode::Return as u8)) {
+        return Err(format!("{current} does not end in a return"));
+    }
+
+    // The length of the instruction starting at each byte of the code, or 0 for operands.
+    let mut lengths = vec![0; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::from_byte(code[offset])
+            .ok_or_else(|| format!("unknown opcode {} in {current}", code[offset]))?;
+        let length = match op {
+            OpCode::Constant => match constant(offset + 1)? {
+                Constant::Function(_) => return Err(format!("expected a value in {current}")),
+                _ => 3,
+            },
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Inherit
+            | OpCode::Method => {
+                name(offset + 1)?;
+                3
+            }
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::Call => {
+                operand(offset + 1)?;
+                2
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                if operand(offset + 1)? as usize >= prototype.upvalue_count {
+                    return Err(format!("missing upvalue in {current}"));
+                }
+                2
+            }
+            OpCode::BuildList | OpCode::BuildMap => {
+                short(offset + 1)?;
+                3
+            }
+            OpCode::Jump | OpCode::JumpIfFalse => {
+                if offset + 3 + short(offset + 1)? > code.len() {
+                    return Err(format!("jump out of {current}"));
+                }
+                3
+            }
+            OpCode::Loop => {
+                if short(offset + 1)? > offset + 3 {
+                    return Err(format!("loop out of {current}"));
+                }
+                3
+            }
+            OpCode::Closure => {
+                let Constant::Function(function) = constant(offset + 1)? else {
+                    return Err(format!("expected a function in {current}"));
+                };
+                for upvalue in 0..function.upvalue_count {
+                    let at = offset + 3 + 2 * upvalue;
+                    let (is_local, index) = (operand(at)?, operand(at + 1)?);
+                    if is_local != 1 && index as usize >= prototype.upvalue_count {
+                        return Err(format!("missing upvalue in {current}"));
+                    }
+                }
+                3 + 2 * function.upvalue_count
+            }
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return => 1,
+        };
+        lengths[offset] = length;
+        offset += length;
+    }
+
+    verify_stack(prototype, &lengths, &current)
+}
+
+/// Check that every path through the code of `prototype`, whose instructions have the `lengths`
+/// found by [`verify`], reaches each instruction with the same number of values on the stack of its
+/// frame, and that no instruction uses more values, or locals, than there are.
+///
+/// It also keeps track of the locals that closures may have captured, because the VM expects their
+/// upvalues to be closed before they leave the stack: only `CloseUpvalue` may drop them, and
+/// `Return` closes the ones below the value it returns.
+///
+/// The operands of the instructions must have been checked already.
+fn verify_stack(prototype: &Prototype, lengths: &[usize], current: &str) -> Result<(), String> {
+    let code = prototype.chunk.code();
+    // The stack height on arriving at each instruction, and the slots that may have been captured
+    // by then, on any of the paths to it followed so far.
+    let mut states: Vec<Option<(usize, BTreeSet<usize>)>> = vec![None; code.len()];
+    // A frame starts out holding the callee and its arguments.
+    let mut pending = vec![(0, 1 + prototype.arity, BTreeSet::new())];
+
+    while let Some((offset, height, mut captured)) = pending.pop() {
+        if lengths.get(offset).is_none_or(|&length| length == 0) {
+            return Err(format!("jump to the middle of an instruction in {current}"));
+        }
+        match &mut states[offset] {
+            Some((known, _)) if *known != height => {
+                return Err(format!("unbalanced stack in {current}"));
+            }
+            Some((_, known)) if captured.is_subset(known) => continue,
+            Some((_, known)) => {
+                known.extend(captured);
+                captured = known.clone();
+            }
+            None => states[offset] = Some((height, captured.clone())),
+        }
+
+        let op = OpCode::from_byte(code[offset]).expect("opcodes were checked");
+        let byte = |at: usize| code[offset + at] as usize;
+        let short = |at: usize| u16::from_be_bytes([code[offset + at], code[offset + at + 1]]);
+        let short = |at: usize| short(at) as usize;
+        // The values each instruction takes off the stack, and the values it puts back.
+        let (pops, pushes) = match op {
+            OpCode::GetLocal | OpCode::SetLocal if byte(1) >= height => {
+                return Err(format!("local slot out of the frame in {current}"));
+            }
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Class => (0, 1),
+            OpCode::Pop | OpCode::DefineGlobal | OpCode::Print | OpCode::CloseUpvalue => (1, 0),
+            OpCode::Inherit | OpCode::Method => (1, 0),
+            OpCode::GetProperty | OpCode::Not | OpCode::Negate => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::GetIndex
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide => (2, 1),
+            OpCode::SetIndex => (3, 1),
+            OpCode::BuildList => (short(1), 1),
+            OpCode::BuildMap => (2 * short(1), 1),
+            OpCode::Call => (byte(1) + 1, 1),
+            OpCode::Closure => {
+                let Constant::Function(function) = &prototype.chunk.constants()[short(1)] else {
+                    unreachable!("closure constants were checked")
+                };
+                for upvalue in 0..function.upvalue_count {
+                    if byte(3 + 2 * upvalue) != 1 {
+                        continue;
+                    }
+                    if byte(4 + 2 * upvalue) >= height {
+                        return Err(format!("captured local out of the frame in {current}"));
+                    }
+                    captured.insert(byte(4 + 2 * upvalue));
+                }
+                (0, 1)
+            }
+            OpCode::SetGlobal | OpCode::SetLocal | OpCode::SetUpvalue | OpCode::JumpIfFalse => {
+                (0, 0)
+            }
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            OpCode::Return => (1, 0),
+        };
+        // Some instructions look at the values below the ones they take.
+        let used = match op {
+            OpCode::Inherit | OpCode::Method => 2,
+            OpCode::SetGlobal | OpCode::SetLocal | OpCode::SetUpvalue | OpCode::JumpIfFalse => 1,
+            _ => pops,
+        };
+        if used > height {
+            return Err(format!("stack underflow in {current}"));
+        }
+        let height = height - pops + pushes;
+        if op == OpCode::CloseUpvalue {
+            captured.remove(&height);
+        }
+        if captured.range(height - pushes..).next().is_some() {
+            return Err(format!(
+                "captured local dropped without closing it in {current}"
+            ));
+        }
+
+        let next = offset + lengths[offset];
+        match op {
+            OpCode::Return => {}
+            OpCode::Jump => pending.push((next + short(1), height, captured)),
+            OpCode::Loop => pending.push((next - short(1), height, captured)),
+            OpCode::JumpIfFalse => {
+                pending.push((next + short(1), height, captured.clone()));
+                pending.push((next, height, captured));
+            }
+            _ => pending.push((next, height, captured)),
+        }
+    }
+
+    Ok(())
+}
+
+fn corrupt(position: usize, reason: &str) -> LoxError {
+    LoxError::without_location(format!("Corrupt bytecode at byte {position}: {reason}."))
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
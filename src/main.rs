@@ -1,163 +1,354 @@
-mod ast;
-mod callable;
-mod environment;
-mod interpreter;
-mod parser;
-mod scanner;
-mod token;
-
+use std::env;
 use std::error::Error;
-use std::fmt::Display;
-use std::fs::read_to_string;
-use std::io::{self, stdin, stdout, BufRead, BufReader, Write};
+use std::fs::{read_to_string, write};
+use std::panic::resume_unwind;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
 
-use environment::Environment;
-use interpreter::Interpreter;
-use parser::Parser;
-use scanner::Scanner;
-use token::{Token, TokenType};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 
-#[derive(Debug, Clone)]
-pub struct LoxError {
-    line: usize,
-    col: usize,
-    place: String, // where
-    message: String,
-}
+use rlox::{Backend, ErrorFormat, Literal, Lox, LoxError};
 
-impl LoxError {
-    fn new(line: usize, col: usize, message: String) -> Self {
-        Self {
-            line,
-            col,
-            place: String::new(),
-            message,
-        }
+fn run_file(path: &String, backend: Backend, error_format: ErrorFormat) {
+    // Compiled bytecode only runs on the VM, so there is no point in asking for it.
+    let backend = if rlox::is_bytecode_file(path) {
+        Backend::Vm
+    } else {
+        backend
+    };
+    let mut lox = Lox::with_backend(backend);
+    lox.set_error_format(error_format);
+    if let Err(e) = lox.run_file(path) {
+        lox.report(&e);
+        // The exit codes of sysexits.h, as clox uses.
+        exit(if e.is_runtime() { 70 } else { 65 });
     }
+}
 
-    fn with_place(line: usize, col: usize, place: String, message: String) -> Self {
-        Self {
-            line,
-            col,
-            place,
-            message,
+fn disassemble_file(path: &String, error_format: ErrorFormat) {
+    let listing = read_to_string(path)
+        .map_err(|e| format!("Error: Could not read '{path}': {e}\n"))
+        .and_then(|source| {
+            rlox::disassemble(&source).map_err(|e| e.format(error_format, &source, Some(path)))
+        });
+    match listing {
+        Ok(listing) => print!("{listing}"),
+        Err(e) => {
+            // Like the errors themselves, the message ends in a newline.
+            eprint!("{e}");
+            exit(65);
         }
     }
+}
 
-    fn from_token(token: &Token, message: String) -> Self {
-        match token.token_type() {
-            TokenType::Eof => {
-                Self::with_place(token.line(), token.col(), "at end".to_string(), message)
-            }
-            _ => Self::with_place(
-                token.line(),
-                token.col(),
-                format!("at '{}'", token.lexeme()),
-                message,
-            ),
+fn compile_file(path: &String, output: &Path, error_format: ErrorFormat) {
+    let bytecode = read_to_string(path)
+        .map_err(|e| format!("Error: Could not read '{path}': {e}\n"))
+        .and_then(|source| {
+            rlox::compile(&source).map_err(|e| e.format(error_format, &source, Some(path)))
+        });
+    let bytecode = match bytecode {
+        Ok(bytecode) => bytecode,
+        Err(e) => {
+            eprint!("{e}");
+            exit(65);
         }
+    };
+    if let Err(e) = write(output, bytecode) {
+        eprintln!("Error: Could not write '{}': {e}", output.display());
+        exit(74);
     }
+}
 
-    pub(crate) fn unexpected_type(token: &Token) -> LoxError {
-        LoxError::from_token(token, format!("Unexpected type of token {token}"))
+/// Run `source` entered at the prompt, and show the value of the expression it ends with,
+/// unless that is nil.
+fn eval_entry(lox: &mut Lox, source: &str) {
+    match lox.eval(source) {
+        Ok(Literal::Nil) => {}
+        Ok(value) => println!("{}", value.quoted()),
+        Err(e) => lox.report(&e),
     }
+}
+
+/// The commands of the prompt, which start with a colon, and what they do.
+const COMMANDS: &str = "\
+:help             Show this list of commands.
+:env              List the global variables and their values.
+:load <file>      Run a script in this session.
+:reset            Forget everything defined in this session.
+:ast <expr>       Show the syntax tree of an expression.
+:tokens <source>  List the tokens of some source code.
+:quit             Leave the prompt, as does the end of input.";
+
+/// A new session for the prompt.
+fn prompt_session(backend: Backend, error_format: ErrorFormat) -> Lox {
+    let mut lox = Lox::with_backend(backend);
+    lox.set_error_format(error_format);
+    lox
+}
 
-    pub(crate) fn return_unwind(keyword: &Token) -> LoxError {
-        LoxError::from_token(keyword, "RETURN".to_string())
+/// Run the prompt `command`, such as `:load file.lox`, in the session `lox`. Returns whether
+/// the prompt should keep going.
+fn run_command(lox: &mut Lox, command: &str, error_format: ErrorFormat) -> bool {
+    let (name, argument) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let argument = argument.trim();
+    // Errors in the argument are found in the argument, not in the code run most recently.
+    let report = |e: LoxError| eprint!("{}", e.format(error_format, argument, None));
+    match name {
+        ":help" => println!("{COMMANDS}"),
+        ":env" => {
+            for (name, value) in lox.global_bindings() {
+                println!("{name} = {}", value.quoted());
+            }
+        }
+        ":load" if !argument.is_empty() => {
+            if let Err(e) = lox.run_file(argument) {
+                lox.report(&e);
+            }
+        }
+        ":load" => eprintln!("Usage: :load <file>"),
+        ":reset" => *lox = prompt_session(lox.backend(), error_format),
+        ":ast" => match rlox::expression_tree(argument) {
+            Ok(tree) => println!("{tree}"),
+            Err(e) => report(e),
+        },
+        ":tokens" => match rlox::token_listing(argument) {
+            Ok(listing) => print!("{listing}"),
+            Err(e) => report(e),
+        },
+        ":quit" => return false,
+        _ => eprintln!("Unknown command '{command}'. Type :help for a list of commands."),
     }
+    true
 }
 
-impl Error for LoxError {}
+/// Completes prompt commands, keywords, and the names of the global variables of the session.
+#[derive(Default)]
+struct PromptHelper {
+    /// The names that can be completed outside of a command, sorted.
+    names: Vec<String>,
+}
 
-impl Display for LoxError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let Self {
-            line,
-            col,
-            place,
-            message,
-        } = self;
-        write!(f, "[line {line}, col {col}] Error {place}: {message}")
+impl PromptHelper {
+    /// Offer the keywords, and the global variables currently defined in `lox`.
+    fn update(&mut self, lox: &Lox) {
+        let globals = lox.global_bindings().into_iter().map(|(name, _)| name);
+        self.names = rlox::keywords()
+            .map(str::to_string)
+            .chain(globals)
+            .collect();
+        self.names.sort();
+        self.names.dedup();
     }
 }
 
-fn run(source: &str) -> Result<String, LoxError> {
-    let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens()?;
+impl Completer for PromptHelper {
+    type Candidate = String;
 
-    let parser = Parser::new(tokens);
-    let parsed = parser.parse()?;
-
-    let mut interpreter = Interpreter::new();
-    let evaluated = interpreter.interpret(parsed)?;
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        if before.starts_with(':') && !before.contains(char::is_whitespace) {
+            let commands = COMMANDS
+                .lines()
+                .filter_map(|line| line.split_whitespace().next());
+            let candidates = commands.filter(|command| command.starts_with(before));
+            return Ok((0, candidates.map(str::to_string).collect()));
+        }
 
-    Ok(evaluated)
+        let start = before
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(0, |(index, c)| index + c.len_utf8());
+        let word = &before[start..];
+        if word.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = self.names.iter().filter(|name| name.starts_with(word));
+        Ok((start, candidates.cloned().collect()))
+    }
 }
 
-fn run_with_env(source: &str, environment: &mut Environment) -> Result<String, LoxError> {
-    let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens()?;
+impl Hinter for PromptHelper {
+    type Hint = String;
+}
 
-    let parser = Parser::new(tokens);
-    let parsed = parser.parse()?;
+impl Highlighter for PromptHelper {}
 
-    let mut interpreter = Interpreter::new();
-    let evaluated = interpreter.interpret_with_env(parsed, environment)?;
+impl Validator for PromptHelper {}
 
-    Ok(evaluated)
-}
+impl Helper for PromptHelper {}
 
-fn run_file(path: &String) -> Result<(), Box<dyn Error>> {
-    let source = read_to_string(path)?;
-    run(&source)?;
-    Ok(())
+/// The file the prompt keeps its history in between sessions, if there is a home directory
+/// to keep it in.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".rlox_history"))
 }
 
-fn run_prompt() -> io::Result<()> {
-    let mut reader = BufReader::new(stdin().lock());
-    let mut stdout = stdout().lock();
+fn run_prompt(backend: Backend, error_format: ErrorFormat) -> rustyline::Result<()> {
+    let mut editor = Editor::<PromptHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(PromptHelper::default()));
+    let history = history_path();
+    if let Some(history) = &history {
+        // There is no history to load before the first session.
+        let _ = editor.load_history(history);
+    }
 
-    let mut env = Environment::new();
+    let mut lox = prompt_session(backend, error_format);
 
-    let mut line = String::new();
+    // The lines of a statement that is not finished yet, or of the one just finished.
+    let mut source = String::new();
     loop {
-        print!("> ");
-        stdout.flush()?;
-        if reader.read_line(&mut line)? == 0 {
-            // EOF encountered. Bye.
-            break;
+        if let Some(helper) = editor.helper_mut() {
+            helper.update(&lox);
+        }
+        let line = match editor.readline(if source.is_empty() { "> " } else { "... " }) {
+            Ok(line) => line,
+            // Ctrl-C abandons the statement being entered.
+            Err(ReadlineError::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                // Run what is left, if anything, so that its errors are reported.
+                if !source.is_empty() {
+                    println!();
+                    eval_entry(&mut lox, &source);
+                }
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
         }
-        match run_with_env(&line, &mut env) {
-            Ok(output) => write!(stdout, "{output}")?,
-            Err(e) => eprintln!("{e}"),
+
+        if source.is_empty() && line.trim_start().starts_with(':') {
+            if !run_command(&mut lox, line.trim(), error_format) {
+                break;
+            }
+            continue;
+        }
+
+        // A blank line gives up on finishing the statement, and runs it as it is.
+        let give_up = !source.is_empty() && line.trim().is_empty();
+        source.push_str(&line);
+        source.push('\n');
+        if !give_up && rlox::is_incomplete(&source) {
+            continue;
         }
-        line.clear();
+        eval_entry(&mut lox, &source);
+        source.clear();
     }
 
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!(
+                "Warning: Could not save the history to '{}': {e}",
+                history.display()
+            );
+        }
+    }
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = std::env::args();
-    match args.len() {
-        1 => run_prompt()?,
-        _ => match args.nth(1).unwrap().as_str() {
-            "run" => run_file(&args.next().unwrap())?,
-            "batch" => {
-                for file in args.collect::<Vec<_>>() {
+/// Take the `--vm` flag out of `args`, and return the backend it selects.
+fn take_backend(args: &mut Vec<String>) -> Backend {
+    match args.iter().position(|arg| arg == "--vm") {
+        Some(index) => {
+            args.remove(index);
+            Backend::Vm
+        }
+        None => Backend::TreeWalker,
+    }
+}
+
+/// Take the `--error-format=...` flag out of `args`, and return the format it selects, or
+/// `None` if it names no format.
+fn take_error_format(args: &mut Vec<String>) -> Option<ErrorFormat> {
+    let Some(index) = args
+        .iter()
+        .position(|arg| arg.starts_with("--error-format="))
+    else {
+        return Some(ErrorFormat::Human);
+    };
+    match args.remove(index).trim_start_matches("--error-format=") {
+        "human" => Some(ErrorFormat::Human),
+        "json" => Some(ErrorFormat::Json),
+        _ => None,
+    }
+}
+
+/// The native stack size of the thread scripts run on. Each call on the tree-walking interpreter
+/// takes up tens of kilobytes of it, so the default would overflow long before the interpreter
+/// reports a stack overflow at the same depth as the VM. Only the part in use is ever allocated.
+const STACK_SIZE: usize = 512 * 1024 * 1024;
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let thread = thread::Builder::new().stack_size(STACK_SIZE).spawn(run)?;
+    thread.join().unwrap_or_else(|panic| resume_unwind(panic))
+}
+
+fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let backend = take_backend(&mut args);
+    let error_format = take_error_format(&mut args);
+    match (args.first().map(String::as_str), error_format) {
+        (None, Some(error_format)) => run_prompt(backend, error_format)?,
+        (Some("run"), Some(error_format)) if args.len() == 2 => {
+            run_file(&args[1], backend, error_format)
+        }
+        (Some("disasm"), Some(error_format)) if args.len() == 2 => {
+            disassemble_file(&args[1], error_format)
+        }
+        (Some("compile"), Some(error_format)) if args.len() == 2 => {
+            let output = Path::new(&args[1]).with_extension("loxc");
+            compile_file(&args[1], &output, error_format)
+        }
+        (Some("compile"), Some(error_format)) if args.len() == 4 && args[2] == "-o" => {
+            compile_file(&args[1], Path::new(&args[3]), error_format)
+        }
+        (Some("batch"), Some(error_format)) => {
+            for file in &args[1..] {
+                // Keep standard error to JSON alone when tools are reading it.
+                if error_format == ErrorFormat::Human {
                     eprintln!("\nRunning '{file}'...");
-                    run_file(&file)?
                 }
+                run_file(file, backend, error_format)
             }
-            _ => {
-                eprintln!("Usage:");
-                eprintln!("\trlox run [script]");
-                eprintln!("\trlox batch [script] [...]");
-                eprintln!("\trlox");
-                exit(64);
-            }
-        },
+        }
+        _ => {
+            eprintln!("Usage:");
+            eprintln!("\trlox [--vm] [--error-format=human|json] run [script]");
+            eprintln!("\trlox [--vm] [--error-format=human|json] batch [script] [...]");
+            eprintln!("\trlox [--error-format=human|json] disasm [script]");
+            eprintln!("\trlox [--error-format=human|json] compile [script] [-o out.loxc]");
+            eprintln!("\trlox [--vm] [--error-format=human|json]");
+            eprintln!();
+            eprintln!("Scripts run on the tree-walking interpreter, unless --vm selects the");
+            eprintln!("bytecode virtual machine. Compiled .loxc files always run on the VM.");
+            eprintln!();
+            eprintln!("Errors are shown for people to read, unless --error-format=json writes");
+            eprintln!("them to standard error as JSON objects, one per line.");
+            eprintln!();
+            eprintln!("Without a script, rlox starts a prompt, which keeps its history in");
+            eprintln!("~/.rlox_history. Type :help at the prompt for its commands.");
+            exit(64);
+        }
     }
 
     Ok(())
@@ -0,0 +1,117 @@
+//! The map value type: a table from keys to values, which remembers the order its keys were
+//! first inserted in.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::diagnostic::Code;
+use crate::token::Literal;
+use crate::RuntimeError;
+
+/// A value that can be used as a map key, in a form that can be hashed.
+///
+/// Two keys are the same exactly when `Literal::is_equal` considers the values they were made
+/// from equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Nil,
+    Bool(bool),
+    /// The bits of the number, with `-0` stored as `0`, since the two are equal.
+    Number(u64),
+    String(String),
+}
+
+impl Key {
+    fn new(key: &Literal) -> Result<Self, RuntimeError> {
+        let invalid = |message| Err(RuntimeError::new(Code::InvalidMapKey, message));
+        match key {
+            Literal::Nil => Ok(Key::Nil),
+            Literal::Bool(b) => Ok(Key::Bool(*b)),
+            // NaN is not equal to anything, so it could never be looked up again.
+            Literal::Number(n) if n.is_nan() => invalid("Map key cannot be NaN.".to_string()),
+            Literal::Number(n) => Ok(Key::Number((n + 0.0).to_bits())),
+            Literal::String(s) => Ok(Key::String(s.clone())),
+            other => invalid(format!(
+                "Map key must be a string, number, bool or nil, but got {}.",
+                other.type_name()
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Map {
+    /// The entries, in insertion order.
+    entries: Vec<(Literal, Literal)>,
+    /// The position of each key in `entries`.
+    indices: HashMap<Key, usize>,
+}
+
+impl Map {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The entries of this map, in the order their keys were first inserted.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(Literal, Literal)> {
+        self.entries.iter()
+    }
+
+    /// The value stored under `key`, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `key` can't be a map key.
+    pub(crate) fn get(&self, key: &Literal) -> Result<Option<&Literal>, RuntimeError> {
+        let index = self.indices.get(&Key::new(key)?);
+        Ok(index.map(|&index| &self.entries[index].1))
+    }
+
+    /// Whether there is a value stored under `key`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `key` can't be a map key.
+    pub(crate) fn contains_key(&self, key: &Literal) -> Result<bool, RuntimeError> {
+        Ok(self.indices.contains_key(&Key::new(key)?))
+    }
+
+    /// Store `value` under `key`, replacing any value already stored under it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `key` can't be a map key.
+    pub(crate) fn insert(&mut self, key: Literal, value: Literal) -> Result<(), RuntimeError> {
+        match self.indices.entry(Key::new(&key)?) {
+            Entry::Occupied(entry) => self.entries[*entry.get()].1 = value,
+            Entry::Vacant(entry) => {
+                entry.insert(self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `key` from this map, and return the value that was stored under it, if any.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `key` can't be a map key.
+    pub(crate) fn remove(&mut self, key: &Literal) -> Result<Option<Literal>, RuntimeError> {
+        let Some(removed) = self.indices.remove(&Key::new(key)?) else {
+            return Ok(None);
+        };
+        // Keep the remaining entries in order, at the cost of moving the later ones up.
+        let (_, value) = self.entries.remove(removed);
+        for index in self.indices.values_mut() {
+            if *index > removed {
+                *index -= 1;
+            }
+        }
+        Ok(Some(value))
+    }
+}
@@ -0,0 +1,214 @@
+//! The standard prelude: functions implemented in Rust that are available to every script.
+
+use std::cell::RefCell;
+use std::io::{stdin, BufRead};
+use std::process::exit;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::callable::NativeFunction;
+use crate::environment::Environment;
+use crate::map::Map;
+use crate::token::Literal;
+use crate::RuntimeError;
+
+/// Define all native functions of the standard prelude in `environment`.
+pub(crate) fn define_prelude(environment: &mut Environment) {
+    let natives = [
+        NativeFunction::new("clock", 0, clock),
+        NativeFunction::new("type", 1, type_of),
+        NativeFunction::new("str", 1, str),
+        NativeFunction::new("num", 1, num),
+        NativeFunction::new("len", 1, len),
+        NativeFunction::new("push", 2, push),
+        NativeFunction::new("pop", 1, pop),
+        NativeFunction::new("insert", 3, insert),
+        NativeFunction::new("remove", 2, remove),
+        NativeFunction::new("slice", 3, slice),
+        NativeFunction::new("keys", 1, keys),
+        NativeFunction::new("values", 1, values),
+        NativeFunction::new("has", 2, has),
+        NativeFunction::new("delete", 2, delete),
+        NativeFunction::new("input", 0, input),
+        NativeFunction::new("exit", 1, exit_with),
+    ];
+
+    for native in natives {
+        environment.define_native(native);
+    }
+}
+
+/// `clock()`: The number of seconds since the Unix epoch.
+fn clock(_arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Literal::Number(now.as_secs_f64()))
+}
+
+/// `type(x)`: The name of the type of `x`.
+fn type_of(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    Ok(Literal::String(arguments[0].type_name().to_string()))
+}
+
+/// `str(x)`: The string representation of `x`, as `print` would show it.
+fn str(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    Ok(Literal::String(arguments[0].to_string()))
+}
+
+/// `num(x)`: Convert a string or bool to a number.
+fn num(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    match &arguments[0] {
+        Literal::Number(n) => Ok(Literal::Number(*n)),
+        Literal::Bool(b) => Ok(Literal::Number(if *b { 1.0 } else { 0.0 })),
+        Literal::String(s) => s
+            .trim()
+            .parse()
+            .map(Literal::Number)
+            .map_err(|_| format!("Cannot convert '{s}' to a number.").into()),
+        other => Err(format!("Cannot convert {other} to a number.").into()),
+    }
+}
+
+/// `len(x)`: The number of characters in the string `x`, the number of elements in the list `x`,
+/// or the number of entries in the map `x`.
+fn len(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    match &arguments[0] {
+        Literal::String(s) => Ok(Literal::Number(s.chars().count() as f64)),
+        Literal::List(list) => Ok(Literal::Number(list.borrow().len() as f64)),
+        Literal::Map(map) => Ok(Literal::Number(map.borrow().len() as f64)),
+        other => Err(format!("Cannot take the length of {other}.").into()),
+    }
+}
+
+/// The list passed as the first argument to the native `name`.
+fn list_argument<'a>(
+    name: &str,
+    arguments: &'a [Literal],
+) -> Result<&'a Rc<RefCell<Vec<Literal>>>, String> {
+    arguments[0].list().ok_or_else(|| {
+        format!(
+            "{name}() expects a list, but got {}.",
+            arguments[0].type_name()
+        )
+    })
+}
+
+/// Use `index` as a position in a list of length `len`: an index of an element, or the end of
+/// the list.
+fn position(index: &Literal, len: usize) -> Result<usize, RuntimeError> {
+    match index.number() {
+        Some(n) if n == len as f64 => Ok(len),
+        _ => index.to_index(len),
+    }
+}
+
+/// `push(list, x)`: Append `x` to the end of `list`.
+fn push(mut arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let value = arguments.pop().expect("arity is checked by the caller");
+    list_argument("push", &arguments)?.borrow_mut().push(value);
+    Ok(Literal::Nil)
+}
+
+/// `pop(list)`: Remove the last element of `list`, and return it.
+fn pop(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    list_argument("pop", &arguments)?
+        .borrow_mut()
+        .pop()
+        .ok_or_else(|| "Cannot pop from an empty list.".to_string().into())
+}
+
+/// `insert(list, i, x)`: Insert `x` into `list` before index `i`. An index equal to the length
+/// of the list appends `x`.
+fn insert(mut arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let value = arguments.pop().expect("arity is checked by the caller");
+    let mut list = list_argument("insert", &arguments)?.borrow_mut();
+    let index = position(&arguments[1], list.len())?;
+    list.insert(index, value);
+    Ok(Literal::Nil)
+}
+
+/// `remove(list, i)`: Remove the element at index `i` from `list`, and return it.
+fn remove(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let mut list = list_argument("remove", &arguments)?.borrow_mut();
+    let index = arguments[1].to_index(list.len())?;
+    Ok(list.remove(index))
+}
+
+/// `slice(list, start, end)`: A new list of the elements of `list` from index `start` up to,
+/// but not including, index `end`.
+fn slice(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let list = list_argument("slice", &arguments)?.borrow();
+    let start = position(&arguments[1], list.len())?;
+    let end = position(&arguments[2], list.len())?;
+    if start > end {
+        return Err(format!("Slice start {start} is after its end {end}.").into());
+    }
+    Ok(Literal::from(list[start..end].to_vec()))
+}
+
+/// `input()`: Read a line from standard input, without the trailing newline. Returns `nil` once
+/// the input is exhausted.
+fn input(_arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let mut line = String::new();
+    if stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?
+        == 0
+    {
+        return Ok(Literal::Nil);
+    }
+
+    let trimmed = line.trim_end_matches(['\n', '\r']);
+    Ok(Literal::String(trimmed.to_string()))
+}
+
+/// `exit(code)`: Exit the process with the given status code.
+fn exit_with(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    match arguments[0] {
+        Literal::Number(code) if code.fract() == 0.0 => exit(code as i32),
+        ref other => Err(format!("Exit code must be an integer, got {other}.").into()),
+    }
+}
+
+/// The map passed as the first argument to the native `name`.
+fn map_argument<'a>(name: &str, arguments: &'a [Literal]) -> Result<&'a Rc<RefCell<Map>>, String> {
+    arguments[0].map().ok_or_else(|| {
+        format!(
+            "{name}() expects a map, but got {}.",
+            arguments[0].type_name()
+        )
+    })
+}
+
+/// `keys(map)`: A list of the keys of `map`, in the order they were first inserted.
+fn keys(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let map = map_argument("keys", &arguments)?.borrow();
+    Ok(Literal::from(
+        map.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>(),
+    ))
+}
+
+/// `values(map)`: A list of the values of `map`, in the same order as `keys(map)`.
+fn values(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let map = map_argument("values", &arguments)?.borrow();
+    Ok(Literal::from(
+        map.iter()
+            .map(|(_, value)| value.clone())
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// `has(map, key)`: Whether `map` has a value stored under `key`.
+fn has(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let map = map_argument("has", &arguments)?.borrow();
+    Ok(Literal::Bool(map.contains_key(&arguments[1])?))
+}
+
+/// `delete(map, key)`: Remove `key` from `map`, and return the value that was stored under it,
+/// or `nil` if there was none.
+fn delete(arguments: Vec<Literal>) -> Result<Literal, RuntimeError> {
+    let mut map = map_argument("delete", &arguments)?.borrow_mut();
+    Ok(map.remove(&arguments[1])?.unwrap_or(Literal::Nil))
+}
@@ -1,4 +1,5 @@
 use crate::ast::{Expr, Stmt};
+use crate::diagnostic::Code;
 use crate::token::TokenType::{self, *};
 use crate::token::{Literal, Token};
 use crate::LoxError;
@@ -9,14 +10,17 @@ type ReturnOrError = Result<Stmt, LoxError>;
 ///
 /// Implements a parser according to the following expression grammar:
 ///
-/// ```
+/// ```text
 /// program        → declaration* EOF ;
 ///
-/// declaration    → funDecl
+/// declaration    → classDecl
+///                | funDecl
 ///                | varDecl
 ///                | statement ;
 ///
 /// statement      → exprStmt
+///                | breakStmt
+///                | continueStmt
 ///                | forStmt
 ///                | ifStmt
 ///                | printStmt
@@ -24,6 +28,8 @@ type ReturnOrError = Result<Stmt, LoxError>;
 ///                | whileStmt
 ///                | block ;
 ///
+/// classDecl      → "class" IDENTIFIER ( "<" IDENTIFIER )?
+///                  "{" function* "}" ;
 /// funDecl        → "fun" function ;
 /// function       → IDENTIFIER "(" parameters? ")" block ;
 /// parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
@@ -34,6 +40,9 @@ type ReturnOrError = Result<Stmt, LoxError>;
 ///
 /// returnStmt     → "return" expression? ";" ;
 ///
+/// breakStmt      → "break" ";" ;
+/// continueStmt   → "continue" ";" ;
+///
 /// whileStmt      → "while" "(" expression ")" statement ;
 ///
 /// ifStmt         → "if" "(" expression ")" statement
@@ -47,7 +56,8 @@ type ReturnOrError = Result<Stmt, LoxError>;
 /// printStmt      → "print" expression ";" ;
 ///
 /// expression     → assignment ;
-/// assignment     → IDENTIFIER "=" assignment
+/// assignment     → ( call "." )? IDENTIFIER "=" assignment
+///                | call "[" expression "]" "=" assignment
 ///                | logic_or ;
 /// logic_or       → logic_and ( "or" logic_and )* ;
 /// logic_and      → equality ( "and" equality )* ;
@@ -57,21 +67,35 @@ type ReturnOrError = Result<Stmt, LoxError>;
 /// term           → factor ( ( "-" | "+" ) factor )* ;
 /// factor         → unary ( ( "/" | "*" ) unary )* ;
 /// unary          → ( "!" | "-" ) unary | call ;
-/// call           → primary ( "(" arguments? ")" )* ;
+/// call           → primary ( "(" arguments? ")" | "." IDENTIFIER | "[" expression "]" )* ;
 /// arguments      → expression ( "," expression )* ;
-/// primary        → "true" | "false" | "nil"
+/// primary        → "true" | "false" | "nil" | "this"
 ///                | NUMBER | STRING
 ///                | "(" expression ")"
-///                | IDENTIFIER ;
+///                | "fun" "(" parameters? ")" block
+///                | "[" ( expression ( "," expression )* )? "]"
+///                | "{" ( entry ( "," entry )* )? "}"
+///                | IDENTIFIER
+///                | "super" "." IDENTIFIER ;
+/// entry          → expression ":" expression ;
 /// ```
 pub(crate) struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    /// The number of loops enclosing the statement being parsed, within the current function.
+    loop_depth: usize,
+    /// The errors found so far. Parsing carries on after each one, at the next statement.
+    errors: Vec<LoxError>,
 }
 
 impl Parser {
     pub(crate) fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+        Self {
+            tokens,
+            current: 0,
+            loop_depth: 0,
+            errors: Vec::new(),
+        }
     }
 
     /// expression     → equality ;
@@ -86,30 +110,47 @@ impl Parser {
     ///                | returnStmt
     ///                | whileStmt
     ///                | block ;
-    fn declaration(&mut self) -> Result<Stmt, LoxError> {
-        if self.match_token_type(Fun) {
-            return self.function("function");
-        }
-        let res = if self.match_token_type(Var) {
+    ///
+    /// Returns `None` if the declaration has an error. The error is recorded, and the parser
+    /// skips ahead to where the next statement seems to start.
+    fn declaration(&mut self) -> Option<Stmt> {
+        // `fun` followed by `(` starts an anonymous function, in an expression statement.
+        let res = if self.check(Fun) && !self.check_next(LeftParen) {
+            self.advance();
+            self.function("function")
+        } else if self.match_token_type(Class) {
+            self.class_declaration()
+        } else if self.match_token_type(Var) {
             self.var_declaration()
         } else {
             self.statement()
         };
 
-        if res.is_err() {
-            self.synchronize()
+        match res {
+            Ok(statement) => Some(statement),
+            Err(error) => {
+                self.errors.push(error);
+                self.synchronize();
+                None
+            }
         }
-
-        res
     }
 
     /// statement      → exprStmt
+    ///                | breakStmt
+    ///                | continueStmt
     ///                | forStmt
     ///                | ifStmt
     ///                | printStmt
     ///                | whileStmt
     ///                | block ;
     fn statement(&mut self) -> Result<Stmt, LoxError> {
+        if self.match_token_type(Break) {
+            return self.break_statement();
+        }
+        if self.match_token_type(Continue) {
+            return self.continue_statement();
+        }
         if self.match_token_type(For) {
             return self.for_statement();
         }
@@ -138,6 +179,7 @@ impl Parser {
     ///                  expression? ";"
     ///                  expression? ")" statement ;
     fn for_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
         self.consume(LeftParen, "Expect '(' after for.".to_string())?;
         let initializer = if self.match_token_type(Semicolon) {
             None
@@ -159,28 +201,18 @@ impl Parser {
         };
         self.consume(RightParen, "Expect ')' after for clauses.".to_string())?;
 
-        let mut body = self.statement()?;
+        let body = self.loop_body()?;
 
-        if let Some(increment) = increment {
-            body = Stmt::Block {
-                statements: vec![
-                    body.clone(),
-                    Stmt::Expression {
-                        expression: increment,
-                    },
-                ],
-            }
-        }
-        let condition = if condition.is_none() {
-            Expr::Literal {
-                value: Literal::Bool(true),
-            }
-        } else {
-            condition.unwrap()
-        };
+        // The increment is not simply appended to the body, because it must also run when an
+        // iteration is cut short by `continue`.
+        let condition = condition.unwrap_or(Expr::Literal {
+            value: Literal::Bool(true),
+            token: keyword,
+        });
         let mut body = Stmt::While {
             condition,
             body: Box::new(body),
+            increment,
         };
         if let Some(initializer) = initializer {
             body = Stmt::Block {
@@ -196,9 +228,51 @@ impl Parser {
         self.consume(LeftParen, "Expect '(' after while.".to_string())?;
         let condition = self.expression()?;
         self.consume(RightParen, "Expect ')' after while condition.".to_string())?;
-        let body = Box::new(self.statement()?);
+        let body = Box::new(self.loop_body()?);
+
+        Ok(Stmt::While {
+            condition,
+            body,
+            increment: None,
+        })
+    }
+
+    /// Parse the body statement of a loop, in which `break` and `continue` are allowed.
+    fn loop_body(&mut self) -> Result<Stmt, LoxError> {
+        self.loop_depth += 1;
+        let body = self.statement();
+        self.loop_depth -= 1;
+        body
+    }
+
+    /// breakStmt      → "break" ";" ;
+    fn break_statement(&mut self) -> ReturnOrError {
+        let keyword = self.previous().clone();
+        if self.loop_depth == 0 {
+            return Err(LoxError::from_token(
+                &keyword,
+                "Can't use 'break' outside of a loop.".to_string(),
+            )
+            .with_code(Code::JumpOutsideLoop));
+        }
+        self.consume(Semicolon, "Expect ';' after 'break'.".to_string())?;
+
+        Ok(Stmt::Break { keyword })
+    }
+
+    /// continueStmt   → "continue" ";" ;
+    fn continue_statement(&mut self) -> ReturnOrError {
+        let keyword = self.previous().clone();
+        if self.loop_depth == 0 {
+            return Err(LoxError::from_token(
+                &keyword,
+                "Can't use 'continue' outside of a loop.".to_string(),
+            )
+            .with_code(Code::JumpOutsideLoop));
+        }
+        self.consume(Semicolon, "Expect ';' after 'continue'.".to_string())?;
 
-        Ok(Stmt::While { condition, body })
+        Ok(Stmt::Continue { keyword })
     }
 
     /// ifStmt         → "if" "(" expression ")" statement
@@ -230,11 +304,49 @@ impl Parser {
         Ok(Stmt::Expression { expression: value })
     }
 
+    /// classDecl      → "class" IDENTIFIER ( "<" IDENTIFIER )?
+    ///                  "{" function* "}" ;
+    fn class_declaration(&mut self) -> Result<Stmt, LoxError> {
+        let name = self
+            .consume(Identifier, "Expect class name.".to_string())?
+            .clone();
+
+        let superclass = if self.match_token_type(Less) {
+            let name = self
+                .consume(Identifier, "Expect superclass name.".to_string())?
+                .clone();
+            Some(Expr::Variable { name, depth: None })
+        } else {
+            None
+        };
+        self.consume(LeftBrace, "Expect '{' before class body.".to_string())?;
+
+        let mut methods = Vec::new();
+        while !self.check(RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+
+        self.consume(RightBrace, "Expect '}' after class body.".to_string())?;
+
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
     fn function(&mut self, kind: &str) -> Result<Stmt, LoxError> {
         let name = self
             .consume(Identifier, format!("Expect {kind} name."))?
             .clone();
         self.consume(LeftParen, format!("Expect '(' after {kind} name."))?;
+        let (params, body) = self.function_body(kind)?;
+
+        Ok(Stmt::Function { name, params, body })
+    }
+
+    /// Parse the parameters and body of a function, after the opening parenthesis.
+    fn function_body(&mut self, kind: &str) -> Result<(Vec<Token>, Vec<Stmt>), LoxError> {
         let mut params = Vec::new();
         if !self.check(RightParen) {
             loop {
@@ -242,7 +354,8 @@ impl Parser {
                     return Err(LoxError::from_token(
                         self.peek(),
                         "Can't have more than 255 parameters.".to_string(),
-                    ));
+                    )
+                    .with_code(Code::TooManyParametersOrArguments));
                 }
 
                 params.push(
@@ -258,24 +371,33 @@ impl Parser {
         self.consume(RightParen, "Expect ')' after parameters.".to_string())?;
 
         self.consume(LeftBrace, format!("Expect '{{' before {kind} body."))?;
-        let body = self.block()?;
+        // A loop around the declaration does not extend into the function body.
+        let enclosing_loop_depth = std::mem::take(&mut self.loop_depth);
+        let body = self.block();
+        self.loop_depth = enclosing_loop_depth;
+        let body = body?;
 
-        Ok(Stmt::Function { name, params, body })
+        Ok((params, body))
     }
 
     /// block          → "{" declaration* "}" ;
     fn block(&mut self) -> Result<Vec<Stmt>, LoxError> {
+        let brace = self.previous().clone();
         let mut statements = Vec::new();
 
         while !self.check(RightBrace) && !self.is_at_end() {
-            statements.push(self.declaration()?);
+            statements.extend(self.declaration());
         }
 
-        self.consume(RightBrace, "Expect '}' after block.".to_string())?;
+        self.consume(RightBrace, "Expect '}' after block.".to_string())
+            .map_err(|error| {
+                error.with_note(format!("The block starts on line {}.", brace.line()))
+            })?;
         Ok(statements)
     }
 
-    /// assignment     → IDENTIFIER "=" assignment
+    /// assignment     → ( call "." )? IDENTIFIER "=" assignment
+    ///                | call "[" expression "]" "=" assignment
     ///                | logic_or ;
     fn assignment(&mut self) -> Result<Expr, LoxError> {
         let expr = self.logic_or()?;
@@ -284,17 +406,40 @@ impl Parser {
             let equals = self.previous().clone();
             let value = self.assignment()?;
 
-            if let Expr::Variable { name } = expr {
-                return Ok(Expr::Assign {
-                    name,
-                    value: Box::new(value),
-                });
+            match expr {
+                Expr::Variable { name, .. } => {
+                    return Ok(Expr::Assign {
+                        name,
+                        value: Box::new(value),
+                        depth: None,
+                    })
+                }
+                Expr::Get { object, name } => {
+                    return Ok(Expr::Set {
+                        object,
+                        name,
+                        value: Box::new(value),
+                    })
+                }
+                Expr::Index {
+                    object,
+                    bracket,
+                    index,
+                } => {
+                    return Ok(Expr::SetIndex {
+                        object,
+                        bracket,
+                        index,
+                        value: Box::new(value),
+                    })
+                }
+                _ => {}
             }
 
-            return Err(LoxError::from_token(
-                &equals,
-                "Invalid assignment target.".to_string(),
-            ));
+            return Err(
+                LoxError::from_token(&equals, "Invalid assignment target.".to_string())
+                    .with_code(Code::InvalidAssignmentTarget),
+            );
         }
 
         Ok(expr)
@@ -461,13 +606,30 @@ impl Parser {
         self.call()
     }
 
-    /// call           → primary ( "(" arguments? ")" )* ;
+    /// call           → primary ( "(" arguments? ")" | "." IDENTIFIER | "[" expression "]" )* ;
     fn call(&mut self) -> Result<Expr, LoxError> {
         let mut expr = self.primary()?;
 
         loop {
             if self.match_token_type(LeftParen) {
                 expr = self.finish_call(expr)?;
+            } else if self.match_token_type(Dot) {
+                let name = self
+                    .consume(Identifier, "Expect property name after '.'.".to_string())?
+                    .clone();
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    name,
+                };
+            } else if self.match_token_type(LeftBracket) {
+                let bracket = self.previous().clone();
+                let index = self.expression()?;
+                self.consume(RightBracket, "Expect ']' after index.".to_string())?;
+                expr = Expr::Index {
+                    object: Box::new(expr),
+                    bracket,
+                    index: Box::new(index),
+                };
             } else {
                 break;
             }
@@ -496,7 +658,8 @@ impl Parser {
                     return Err(LoxError::from_token(
                         self.peek(),
                         "Can't have more than 255 arguments.".to_string(),
-                    ));
+                    )
+                    .with_code(Code::TooManyParametersOrArguments));
                 }
                 arguments.push(self.expression()?);
                 if !self.match_token_type(Comma) {
@@ -508,26 +671,34 @@ impl Parser {
         Ok(arguments)
     }
 
-    /// primary        → "true" | "false" | "nil"
+    /// primary        → "true" | "false" | "nil" | "this"
     ///                | NUMBER | STRING
     ///                | "(" expression ")"
-    ///                | IDENTIFIER ;
+    ///                | "fun" "(" parameters? ")" block
+    ///                | "[" ( expression ( "," expression )* )? "]"
+    ///                | "{" ( entry ( "," entry )* )? "}"
+    ///                | IDENTIFIER
+    ///                | "super" "." IDENTIFIER ;
+    /// entry          → expression ":" expression ;
     fn primary(&mut self) -> Result<Expr, LoxError> {
         if self.match_token_type(False) {
             return Ok(Expr::Literal {
                 value: Literal::Bool(false),
+                token: self.previous().clone(),
             });
         }
 
         if self.match_token_type(True) {
             return Ok(Expr::Literal {
                 value: Literal::Bool(true),
+                token: self.previous().clone(),
             });
         }
 
         if self.match_token_type(Nil) {
             return Ok(Expr::Literal {
                 value: Literal::Nil,
+                token: self.previous().clone(),
             });
         }
 
@@ -536,12 +707,45 @@ impl Parser {
                 // I believe the use of previous after we have checked it using
                 // match_token_type allows us to safely unwrap here.
                 value: self.previous().literal().unwrap(),
+                token: self.previous().clone(),
+            });
+        }
+
+        if self.match_token_type(Super) {
+            let keyword = self.previous().clone();
+            self.consume(Dot, "Expect '.' after 'super'.".to_string())?;
+            let method = self
+                .consume(Identifier, "Expect superclass method name.".to_string())?
+                .clone();
+            return Ok(Expr::Super {
+                keyword,
+                method,
+                depth: None,
+            });
+        }
+
+        if self.match_token_type(This) {
+            return Ok(Expr::This {
+                keyword: self.previous().clone(),
+                depth: None,
             });
         }
 
         if self.match_token_type(Identifier) {
             return Ok(Expr::Variable {
                 name: self.previous().clone(),
+                depth: None,
+            });
+        }
+
+        if self.match_token_type(Fun) {
+            let keyword = self.previous().clone();
+            self.consume(LeftParen, "Expect '(' after 'fun'.".to_string())?;
+            let (params, body) = self.function_body("function")?;
+            return Ok(Expr::Function {
+                keyword,
+                params,
+                body,
             });
         }
 
@@ -553,11 +757,45 @@ impl Parser {
             });
         }
 
+        if self.match_token_type(LeftBracket) {
+            let bracket = self.previous().clone();
+            let mut elements = Vec::new();
+            if !self.check(RightBracket) {
+                loop {
+                    elements.push(self.expression()?);
+                    if !self.match_token_type(Comma) {
+                        break;
+                    }
+                }
+            }
+            self.consume(RightBracket, "Expect ']' after list elements.".to_string())?;
+            return Ok(Expr::List { bracket, elements });
+        }
+
+        // A brace only starts a map here: at the start of a statement, it starts a block.
+        if self.match_token_type(LeftBrace) {
+            let brace = self.previous().clone();
+            let mut entries = Vec::new();
+            if !self.check(RightBrace) {
+                loop {
+                    let key = self.expression()?;
+                    self.consume(Colon, "Expect ':' after map key.".to_string())?;
+                    let value = self.expression()?;
+                    entries.push((key, value));
+                    if !self.match_token_type(Comma) {
+                        break;
+                    }
+                }
+            }
+            self.consume(RightBrace, "Expect '}' after map entries.".to_string())?;
+            return Ok(Expr::Map { brace, entries });
+        }
+
         let unexpected = self.peek();
-        Err(LoxError::from_token(
-            unexpected,
-            "Expect expression.".to_string(),
-        ))
+        Err(
+            LoxError::from_token(unexpected, "Expect expression.".to_string())
+                .with_code(Code::ExpectExpression),
+        )
     }
 
     fn peek(&self) -> &Token {
@@ -605,6 +843,13 @@ impl Parser {
         self.peek().token_type() == token_type
     }
 
+    /// Whether the token after the current one is of `token_type`.
+    fn check_next(&self, token_type: TokenType) -> bool {
+        self.tokens
+            .get(self.current + 1)
+            .is_some_and(|token| token.token_type() == token_type)
+    }
+
     fn consume(
         &mut self,
         until: TokenType,
@@ -616,7 +861,14 @@ impl Parser {
 
         // If we do not encounter the check, we have have an error on our hands.
         let unexpected = self.peek();
-        Err(LoxError::from_token(unexpected, message))
+        let error = LoxError::from_token(unexpected, message);
+        Err(match until {
+            Semicolon => error.with_code(Code::ExpectSemicolon),
+            Identifier => error.with_code(Code::ExpectName),
+            // Tokens left over after a lone expression have no code of their own.
+            Eof => error,
+            _ => error.with_code(Code::ExpectToken),
+        })
     }
 
     fn synchronize(&mut self) {
@@ -636,12 +888,33 @@ impl Parser {
         }
     }
 
-    pub(crate) fn parse(mut self) -> Result<Vec<Stmt>, LoxError> {
+    /// Parse the whole program.
+    ///
+    /// # Errors
+    ///
+    /// This function will return every syntax error in the program, in the order they were
+    /// found, if there are any.
+    pub(crate) fn parse(mut self) -> Result<Vec<Stmt>, Vec<LoxError>> {
         let mut statements = Vec::new();
         while !self.is_at_end() {
-            statements.push(self.declaration()?)
+            statements.extend(self.declaration());
         }
 
-        Ok(statements)
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Parse a single expression, which must make up all of the tokens.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the first syntax error in the expression, if there is one.
+    pub(crate) fn parse_expression(mut self) -> Result<Expr, LoxError> {
+        let expression = self.expression()?;
+        self.consume(Eof, "Expect end of expression.".to_string())?;
+        Ok(expression)
     }
 }
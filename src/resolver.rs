@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+
+use crate::ast::{Depth, Expr, Stmt};
+use crate::diagnostic::Code;
+use crate::token::Token;
+use crate::LoxError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+/// A static pass over the syntax tree that runs between the parser and the interpreter.
+///
+/// The resolver figures out, for every use of a local variable, how many environments lie
+/// between the use and the declaration. It writes that number into the `depth` of the expression,
+/// so that the interpreter can go straight to the right environment. Variables that are not found
+/// in any local scope are left at `None`, and are looked up in the globals.
+///
+/// Along the way, it reports some mistakes that can be caught before running anything.
+pub(crate) struct Resolver {
+    /// Stack of local scopes. Each maps a name to whether its initializer has been resolved yet.
+    scopes: Vec<HashMap<String, bool>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+}
+
+impl Resolver {
+    pub(crate) fn new() -> Self {
+        Self {
+            scopes: Vec::new(),
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+        }
+    }
+
+    pub(crate) fn resolve(mut self, statements: &mut [Stmt]) -> Result<(), LoxError> {
+        self.resolve_statements(statements)
+    }
+
+    fn resolve_statements(&mut self, statements: &mut [Stmt]) -> Result<(), LoxError> {
+        for statement in statements {
+            self.resolve_statement(statement)?;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_statement(&mut self, statement: &mut Stmt) -> Result<(), LoxError> {
+        match statement {
+            Stmt::Block { statements } => {
+                self.begin_scope();
+                self.resolve_statements(statements)?;
+                self.end_scope();
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+
+                self.declare(name)?;
+                self.define(name);
+
+                if let Some(superclass) = superclass {
+                    let Expr::Variable {
+                        name: superclass_name,
+                        ..
+                    } = superclass
+                    else {
+                        unreachable!()
+                    };
+                    if superclass_name.lexeme() == name.lexeme() {
+                        return Err(LoxError::from_token(
+                            superclass_name,
+                            "A class can't inherit from itself.".to_string(),
+                        )
+                        .with_code(Code::InheritFromSelf));
+                    }
+
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expression(superclass)?;
+
+                    self.begin_scope();
+                    self.define_name("super");
+                }
+
+                self.begin_scope();
+                self.define_name("this");
+
+                for method in methods {
+                    let Stmt::Function { name, params, body } = method else {
+                        unreachable!()
+                    };
+                    let function_type = if name.lexeme() == "init" {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    self.resolve_function(params, body, function_type)?;
+                }
+
+                self.end_scope();
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+
+                self.current_class = enclosing_class;
+            }
+            Stmt::Break { .. } | Stmt::Continue { .. } => {}
+            Stmt::Expression { expression } => self.resolve_expression(expression)?,
+            Stmt::Function { name, params, body } => {
+                // Define the name eagerly, so that a function can refer to itself recursively.
+                self.declare(name)?;
+                self.define(name);
+                self.resolve_function(params, body, FunctionType::Function)?;
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expression(condition)?;
+                self.resolve_statement(then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    self.resolve_statement(else_branch)?;
+                }
+            }
+            Stmt::Print { expression } => self.resolve_expression(expression)?,
+            Stmt::Return { keyword, value } => {
+                if self.current_function == FunctionType::None {
+                    return Err(LoxError::from_token(
+                        keyword,
+                        "Can't return from top-level code.".to_string(),
+                    )
+                    .with_code(Code::ReturnFromTopLevel));
+                }
+
+                if let Some(value) = value {
+                    if self.current_function == FunctionType::Initializer {
+                        return Err(LoxError::from_token(
+                            keyword,
+                            "Can't return a value from an initializer.".to_string(),
+                        )
+                        .with_code(Code::ReturnValueFromInitializer)
+                        .with_note("An initializer always returns the new instance.".to_string()));
+                    }
+                    self.resolve_expression(value)?;
+                }
+            }
+            Stmt::Var { name, initializer } => {
+                self.declare(name)?;
+                if let Some(initializer) = initializer {
+                    self.resolve_expression(initializer)?;
+                }
+                self.define(name);
+            }
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
+                self.resolve_expression(condition)?;
+                self.resolve_statement(body)?;
+                if let Some(increment) = increment {
+                    self.resolve_expression(increment)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resolve_expression(&mut self, expression: &mut Expr) -> Result<(), LoxError> {
+        match expression {
+            Expr::Literal { .. } => {}
+            Expr::Variable { name, depth } => {
+                if let Some(scope) = self.scopes.last() {
+                    if scope.get(name.lexeme()) == Some(&false) {
+                        return Err(LoxError::from_token(
+                            name,
+                            "Can't read local variable in its own initializer.".to_string(),
+                        )
+                        .with_code(Code::ReadInOwnInitializer));
+                    }
+                }
+
+                *depth = self.resolve_local(name);
+            }
+            Expr::Assign { name, value, depth } => {
+                self.resolve_expression(value)?;
+                *depth = self.resolve_local(name);
+            }
+            Expr::Logical { left, right, .. } | Expr::Binary { left, right, .. } => {
+                self.resolve_expression(left)?;
+                self.resolve_expression(right)?;
+            }
+            Expr::Unary { right, .. } => self.resolve_expression(right)?,
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.resolve_expression(callee)?;
+                for argument in arguments {
+                    self.resolve_expression(argument)?;
+                }
+            }
+            Expr::Function { params, body, .. } => {
+                self.resolve_function(params, body, FunctionType::Function)?
+            }
+            Expr::Grouping { expression } => self.resolve_expression(expression)?,
+            Expr::List { elements, .. } => {
+                for element in elements {
+                    self.resolve_expression(element)?;
+                }
+            }
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.resolve_expression(key)?;
+                    self.resolve_expression(value)?;
+                }
+            }
+            Expr::Index { object, index, .. } => {
+                self.resolve_expression(object)?;
+                self.resolve_expression(index)?;
+            }
+            Expr::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.resolve_expression(object)?;
+                self.resolve_expression(index)?;
+                self.resolve_expression(value)?;
+            }
+            Expr::Get { object, .. } => self.resolve_expression(object)?,
+            Expr::Set { object, value, .. } => {
+                self.resolve_expression(value)?;
+                self.resolve_expression(object)?;
+            }
+            Expr::Super { keyword, depth, .. } => {
+                match self.current_class {
+                    ClassType::None => {
+                        return Err(LoxError::from_token(
+                            keyword,
+                            "Can't use 'super' outside of a class.".to_string(),
+                        )
+                        .with_code(Code::SuperOutsideClass))
+                    }
+                    ClassType::Class => {
+                        return Err(LoxError::from_token(
+                            keyword,
+                            "Can't use 'super' in a class with no superclass.".to_string(),
+                        )
+                        .with_code(Code::SuperWithoutSuperclass))
+                    }
+                    ClassType::Subclass => {}
+                }
+
+                *depth = self.resolve_local(keyword);
+            }
+            Expr::This { keyword, depth } => {
+                if self.current_class == ClassType::None {
+                    return Err(LoxError::from_token(
+                        keyword,
+                        "Can't use 'this' outside of a class.".to_string(),
+                    )
+                    .with_code(Code::ThisOutsideClass));
+                }
+
+                *depth = self.resolve_local(keyword);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resolve_function(
+        &mut self,
+        params: &[Token],
+        body: &mut [Stmt],
+        function_type: FunctionType,
+    ) -> Result<(), LoxError> {
+        let enclosing_function = self.current_function;
+        self.current_function = function_type;
+
+        self.begin_scope();
+        for param in params {
+            self.declare(param)?;
+            self.define(param);
+        }
+        self.resolve_statements(body)?;
+        self.end_scope();
+
+        self.current_function = enclosing_function;
+        Ok(())
+    }
+
+    /// Find the number of scopes between the innermost scope and the one declaring `name`.
+    fn resolve_local(&self, name: &Token) -> Depth {
+        self.scopes
+            .iter()
+            .rev()
+            .position(|scope| scope.contains_key(name.lexeme()))
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Declare a name in the innermost scope, without marking it as ready for use yet.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the name is already declared in a local scope.
+    fn declare(&mut self, name: &Token) -> Result<(), LoxError> {
+        let Some(scope) = self.scopes.last_mut() else {
+            // Globals may be redeclared freely.
+            return Ok(());
+        };
+
+        if scope.contains_key(name.lexeme()) {
+            return Err(LoxError::from_token(
+                name,
+                "Already a variable with this name in this scope.".to_string(),
+            )
+            .with_code(Code::AlreadyDeclared));
+        }
+
+        scope.insert(name.lexeme().to_string(), false);
+        Ok(())
+    }
+
+    fn define(&mut self, name: &Token) {
+        self.define_name(name.lexeme());
+    }
+
+    fn define_name(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), true);
+        }
+    }
+}
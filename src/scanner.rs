@@ -1,6 +1,29 @@
+use crate::diagnostic::Code;
 use crate::token::{Literal, Token, TokenType};
 use crate::LoxError;
 
+/// The reserved words, and the types of their tokens.
+pub(crate) const KEYWORDS: [(&str, TokenType); 18] = [
+    ("and", TokenType::And),
+    ("break", TokenType::Break),
+    ("class", TokenType::Class),
+    ("continue", TokenType::Continue),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("fun", TokenType::Fun),
+    ("for", TokenType::For),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
+
 pub(crate) struct Scanner<'s> {
     source: &'s str,
     tokens: Vec<Token>,
@@ -8,6 +31,8 @@ pub(crate) struct Scanner<'s> {
     current: usize,
     /// 1-indexed line number.
     line: usize,
+    /// The errors found so far. Scanning carries on after each one.
+    errors: Vec<LoxError>,
 }
 
 impl<'s> Scanner<'s> {
@@ -18,17 +43,35 @@ impl<'s> Scanner<'s> {
             start: 0,
             current: 0,
             line: 1,
+            errors: Vec::new(),
         }
     }
 
-    pub(crate) fn scan_tokens(mut self) -> Result<Vec<Token>, LoxError> {
+    /// Number the lines of the source from `line`, as if it came after `line - 1` other lines.
+    pub(crate) fn with_first_line(self, line: usize) -> Self {
+        Self { line, ..self }
+    }
+
+    /// Scan the whole source, and return its tokens along with every error found in it.
+    ///
+    /// Characters that fail to scan are skipped, so the tokens can still be parsed to find any
+    /// further errors.
+    pub(crate) fn scan_tokens(mut self) -> (Vec<Token>, Vec<LoxError>) {
         while !self.is_at_end() {
             self.start = self.current;
-            self.scan_token()?;
+            if let Err(error) = self.scan_token() {
+                self.errors.push(error);
+            }
         }
 
-        self.push_new_token_at_line(TokenType::Eof, "".to_string(), None, self.line, self.col());
-        Ok(self.tokens)
+        // Errors at the end point just past the last character, rather than at a line after the
+        // final newline.
+        let end = self.source.trim_end_matches(['\n', '\r']).len();
+        let line = self.line - self.source[end..].matches('\n').count();
+        let col = self.column(end);
+        let eof = Token::new(TokenType::Eof, "".to_string(), None, line, col, col);
+        self.tokens.push(eof);
+        (self.tokens, self.errors)
     }
 
     fn is_at_end(&self) -> bool {
@@ -43,6 +86,9 @@ impl<'s> Scanner<'s> {
             ')' => self.push_token(RightParen),
             '{' => self.push_token(LeftBrace),
             '}' => self.push_token(RightBrace),
+            '[' => self.push_token(LeftBracket),
+            ']' => self.push_token(RightBracket),
+            ':' => self.push_token(Colon),
             ',' => self.push_token(Comma),
             '.' => self.push_token(Dot),
             '-' => self.push_token(Minus),
@@ -87,11 +133,17 @@ impl<'s> Scanner<'s> {
 
             // Anything else, we throw an error.
             _ => {
+                // Skip the rest of a character that takes up more than one byte, so that it is
+                // only reported once.
+                while self.peek().is_some_and(|c| c as u32 & 0xC0 == 0x80) {
+                    self.advance();
+                }
                 return Err(LoxError::new(
                     self.line,
-                    self.col(),
+                    self.column(self.start),
                     "Unexpected character.".to_string(),
-                ))
+                )
+                .with_code(Code::UnexpectedCharacter));
             }
         }
 
@@ -118,28 +170,34 @@ impl<'s> Scanner<'s> {
 
     fn push_new_token(&mut self, token_type: TokenType, literal: Option<Literal>) {
         let text = self.source[self.start..self.current].to_owned();
-        self.push_new_token_at_line(token_type, text, literal, self.line, self.col())
-    }
-
-    fn push_new_token_at_line(
-        &mut self,
-        token_type: TokenType,
-        lexeme: String,
-        literal: Option<Literal>,
-        line: usize,
-        col: usize,
-    ) {
-        self.tokens
-            .push(Token::new(token_type, lexeme, literal, line, col))
+        // A token is on the line it ends on, so one that spans lines starts at the beginning of
+        // that line as far as its span is concerned.
+        let col = if text.contains('\n') {
+            1
+        } else {
+            self.column(self.start)
+        };
+        // The column of the last character, which may take up more than one byte.
+        let end_col = self.column(self.current) - 1;
+        self.tokens.push(Token::new(
+            token_type, text, literal, self.line, col, end_col,
+        ))
     }
 
-    pub(crate) fn col(&self) -> usize {
-        // TODO: I wonder whether this unwrap_or is actually ever hit. Is there an actual case
-        // where None might occur? (curiosity bikeshed)
-        match self.source[..self.current].lines().last() {
-            None => 0,
-            Some(line) => line.len(),
-        }
+    /// The 1-indexed column of the character starting at byte `index`, within its line. Columns
+    /// count characters rather than bytes, the way the diagnostics underline them.
+    fn column(&self, index: usize) -> usize {
+        let bytes = self.source.as_bytes();
+        let line_start = bytes[..index]
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        // Every byte of UTF-8 but the continuation bytes starts a character.
+        let chars = bytes[line_start..index]
+            .iter()
+            .filter(|&&byte| byte & 0xC0 != 0x80)
+            .count();
+        chars + 1
     }
 
     /// Return `true` and advance if the current source `char` equals `expected`. Otherwise, return
@@ -187,6 +245,7 @@ impl<'s> Scanner<'s> {
     }
 
     pub(crate) fn string(&mut self) -> Result<(), LoxError> {
+        let start_line = self.line;
         // TODO: This is some terrible work. There must be a nice way to do this. Shame let
         // chaining is not yet here...
         while {
@@ -203,10 +262,11 @@ impl<'s> Scanner<'s> {
             // We have reached the end of the source code without termination of the string
             // literal.
             return Err(LoxError::new(
-                self.line,
-                self.col(),
+                start_line,
+                self.column(self.start),
                 "Unterminated string.".to_string(),
-            ));
+            )
+            .with_code(Code::UnterminatedString));
         }
 
         // We advance for the closing ".
@@ -255,25 +315,11 @@ impl<'s> Scanner<'s> {
             self.advance();
         }
 
-        use TokenType::*;
-        let token_type = match &self.source[self.start..self.current] {
-            "and" => And,
-            "class" => Class,
-            "else" => Else,
-            "false" => False,
-            "fun" => Fun,
-            "for" => For,
-            "if" => If,
-            "nil" => Nil,
-            "or" => Or,
-            "print" => Print,
-            "return" => Return,
-            "this" => This,
-            "true" => True,
-            "var" => Var,
-            "while" => While,
-            _ => Identifier,
-        };
+        let text = &self.source[self.start..self.current];
+        let token_type = KEYWORDS
+            .iter()
+            .find(|&&(keyword, _)| keyword == text)
+            .map_or(TokenType::Identifier, |&(_, token_type)| token_type);
 
         self.push_token(token_type);
 
@@ -1,9 +1,13 @@
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::{fmt::Display, ops::Deref};
 
-use crate::{
-    callable::{Callable, Function},
-    environment::Environment,
-};
+use crate::callable::{Callable, Function, NativeFunction};
+use crate::class::{Class, Instance};
+use crate::diagnostic::Code;
+use crate::map::Map;
+use crate::vm;
+use crate::RuntimeError;
 
 #[derive(Debug, Clone)]
 pub struct Token {
@@ -11,7 +15,10 @@ pub struct Token {
     lexeme: String,
     literal: Option<Literal>,
     line: usize,
+    /// The 1-indexed column of the first character of the token, on `line`.
     col: usize,
+    /// The 1-indexed column of the last character of the token, on `line`.
+    end_col: usize,
 }
 
 impl Token {
@@ -21,6 +28,7 @@ impl Token {
         literal: Option<Literal>,
         line: usize,
         col: usize,
+        end_col: usize,
     ) -> Self {
         Self {
             token_type,
@@ -28,6 +36,7 @@ impl Token {
             literal,
             line,
             col,
+            end_col,
         }
     }
 
@@ -50,6 +59,10 @@ impl Token {
     pub(crate) fn col(&self) -> usize {
         self.col
     }
+
+    pub(crate) fn end_col(&self) -> usize {
+        self.end_col
+    }
 }
 
 impl Display for Token {
@@ -66,15 +79,123 @@ impl Display for Token {
 
 #[derive(Debug, Clone)]
 pub enum Literal {
+    #[allow(dead_code)]
     Identifier(String),
-    Fun(Box<Function>),
+    Fun(LoxFunction),
+    Native(Rc<NativeFunction>),
+    Class(LoxClass),
+    Instance(LoxInstance),
+    /// A list, shared by reference: every copy of the value sees changes made through any other.
+    List(Rc<RefCell<Vec<Literal>>>),
+    /// A map, shared by reference like a list.
+    Map(Rc<RefCell<Map>>),
     String(String),
     Number(f64),
     Nil,
     Bool(bool),
 }
 
+/// A function or method written in Lox.
+///
+/// Each backend represents functions in its own way, so there is nothing to do with one but call
+/// it from Lox, compare it, or show it.
+#[derive(Debug, Clone)]
+pub struct LoxFunction(pub(crate) FunctionObject);
+
+#[derive(Debug, Clone)]
+pub(crate) enum FunctionObject {
+    TreeWalker(Box<Function>),
+    Closure(Rc<vm::Closure>),
+    BoundMethod(Rc<vm::BoundMethod>),
+}
+
+/// A class declared in Lox. Like a [`LoxFunction`], it is made by and for one backend.
+#[derive(Debug, Clone)]
+pub struct LoxClass(pub(crate) ClassObject);
+
+#[derive(Debug, Clone)]
+pub(crate) enum ClassObject {
+    TreeWalker(Rc<Class>),
+    Vm(Rc<vm::Class>),
+}
+
+/// An instance of a [`LoxClass`].
+#[derive(Debug, Clone)]
+pub struct LoxInstance(pub(crate) InstanceObject);
+
+#[derive(Debug, Clone)]
+pub(crate) enum InstanceObject {
+    TreeWalker(Rc<RefCell<Instance>>),
+    Vm(Rc<RefCell<vm::Instance>>),
+}
+
 impl Literal {
+    pub(crate) fn function(function: Function) -> Self {
+        Literal::Fun(LoxFunction(FunctionObject::TreeWalker(Box::new(function))))
+    }
+
+    pub(crate) fn closure(closure: Rc<vm::Closure>) -> Self {
+        Literal::Fun(LoxFunction(FunctionObject::Closure(closure)))
+    }
+
+    pub(crate) fn bound_method(bound: Rc<vm::BoundMethod>) -> Self {
+        Literal::Fun(LoxFunction(FunctionObject::BoundMethod(bound)))
+    }
+
+    pub(crate) fn class(class: Rc<Class>) -> Self {
+        Literal::Class(LoxClass(ClassObject::TreeWalker(class)))
+    }
+
+    pub(crate) fn vm_class(class: Rc<vm::Class>) -> Self {
+        Literal::Class(LoxClass(ClassObject::Vm(class)))
+    }
+
+    pub(crate) fn instance(instance: Rc<RefCell<Instance>>) -> Self {
+        Literal::Instance(LoxInstance(InstanceObject::TreeWalker(instance)))
+    }
+
+    pub(crate) fn vm_instance(instance: Rc<RefCell<vm::Instance>>) -> Self {
+        Literal::Instance(LoxInstance(InstanceObject::Vm(instance)))
+    }
+
+    pub(crate) fn as_closure(&self) -> Option<&Rc<vm::Closure>> {
+        match self {
+            Literal::Fun(LoxFunction(FunctionObject::Closure(closure))) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_class(&self) -> Option<&Rc<Class>> {
+        match self {
+            Literal::Class(LoxClass(ClassObject::TreeWalker(class))) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_vm_class(&self) -> Option<&Rc<vm::Class>> {
+        match self {
+            Literal::Class(LoxClass(ClassObject::Vm(class))) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_instance(&self) -> Option<&Rc<RefCell<Instance>>> {
+        match self {
+            Literal::Instance(LoxInstance(InstanceObject::TreeWalker(instance))) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_vm_instance(&self) -> Option<&Rc<RefCell<vm::Instance>>> {
+        match self {
+            Literal::Instance(LoxInstance(InstanceObject::Vm(instance))) => Some(instance),
+            _ => None,
+        }
+    }
+}
+
+impl Literal {
+    #[allow(dead_code)]
     fn identifier(&self) -> Option<&String> {
         match self {
             Literal::Identifier(s) => Some(s),
@@ -89,6 +210,25 @@ impl Literal {
         }
     }
 
+    pub(crate) fn list(&self) -> Option<&Rc<RefCell<Vec<Literal>>>> {
+        match self {
+            Literal::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn map(&self) -> Option<&Rc<RefCell<Map>>> {
+        match self {
+            Literal::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    /// Display this value the way it is shown inside of a list or map, with strings quoted.
+    pub fn quoted(&self) -> impl Display + '_ {
+        Element(self)
+    }
+
     pub(crate) fn number(&self) -> Option<f64> {
         match self {
             Literal::Number(n) => Some(*n),
@@ -122,7 +262,29 @@ impl Literal {
     pub(crate) fn is_equal(left: Literal, right: Literal) -> Self {
         let equality = match (left, right) {
             (Literal::Identifier(a), Literal::Identifier(b)) => a == b,
-            (Literal::Fun(a), Literal::Fun(b)) => a.name().lexeme() == b.name().lexeme(),
+            (Literal::Fun(LoxFunction(a)), Literal::Fun(LoxFunction(b))) => match (a, b) {
+                (FunctionObject::TreeWalker(a), FunctionObject::TreeWalker(b)) => a.is_same(&b),
+                (FunctionObject::Closure(a), FunctionObject::Closure(b)) => Rc::ptr_eq(&a, &b),
+                (FunctionObject::BoundMethod(a), FunctionObject::BoundMethod(b)) => a.is_same(&b),
+                _ => false,
+            },
+            (Literal::Native(a), Literal::Native(b)) => Rc::ptr_eq(&a, &b),
+            (Literal::Class(LoxClass(a)), Literal::Class(LoxClass(b))) => match (a, b) {
+                (ClassObject::TreeWalker(a), ClassObject::TreeWalker(b)) => Rc::ptr_eq(&a, &b),
+                (ClassObject::Vm(a), ClassObject::Vm(b)) => Rc::ptr_eq(&a, &b),
+                _ => false,
+            },
+            (Literal::Instance(LoxInstance(a)), Literal::Instance(LoxInstance(b))) => {
+                match (a, b) {
+                    (InstanceObject::TreeWalker(a), InstanceObject::TreeWalker(b)) => {
+                        Rc::ptr_eq(&a, &b)
+                    }
+                    (InstanceObject::Vm(a), InstanceObject::Vm(b)) => Rc::ptr_eq(&a, &b),
+                    _ => false,
+                }
+            }
+            (Literal::List(a), Literal::List(b)) => Rc::ptr_eq(&a, &b),
+            (Literal::Map(a), Literal::Map(b)) => Rc::ptr_eq(&a, &b),
             (Literal::String(a), Literal::String(b)) => a == b,
             (Literal::Number(a), Literal::Number(b)) => a == b,
             (Literal::Nil, Literal::Nil) => true,
@@ -159,9 +321,93 @@ impl Literal {
         left.operate_number(|n| f(n, right))
     }
 
-    pub(crate) fn callable(&self) -> Option<impl Callable> {
+    /// Use this value as an index into a list of length `len`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if this is not an integer, or if it is out of bounds.
+    pub(crate) fn to_index(&self, len: usize) -> Result<usize, RuntimeError> {
+        let invalid = |message| Err(RuntimeError::new(Code::InvalidListIndex, message));
+        let Literal::Number(n) = self else {
+            return invalid(format!(
+                "List index must be a number, but got {}.",
+                self.type_name()
+            ));
+        };
+        if n.fract() != 0.0 {
+            return invalid(format!("List index must be an integer, but got {n}."));
+        }
+        if *n < 0.0 || *n >= len as f64 {
+            return invalid(format!(
+                "List index {n} is out of bounds for a list of length {len}."
+            ));
+        }
+        Ok(*n as usize)
+    }
+
+    /// Index into this list or map, as in `self[index]`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if this is neither a list nor a map, or if there is
+    /// no element at `index`.
+    pub(crate) fn get_index(&self, index: &Literal) -> Result<Literal, RuntimeError> {
+        match self {
+            Literal::List(list) => {
+                let list = list.borrow();
+                Ok(list[index.to_index(list.len())?].clone())
+            }
+            Literal::Map(map) => map.borrow().get(index)?.cloned().ok_or_else(|| {
+                RuntimeError::new(
+                    Code::UndefinedKey,
+                    format!("Undefined key {}.", Element(index)),
+                )
+            }),
+            _ => Err(not_indexable()),
+        }
+    }
+
+    /// Assign to an element of this list or map, as in `self[index] = value`. Assigning to a
+    /// new key adds it to a map, but lists don't grow this way.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if this is neither a list nor a map, or if `index`
+    /// is not a valid index into it.
+    pub(crate) fn set_index(&self, index: Literal, value: Literal) -> Result<(), RuntimeError> {
+        match self {
+            Literal::List(list) => {
+                let mut list = list.borrow_mut();
+                let index = index.to_index(list.len())?;
+                list[index] = value;
+                Ok(())
+            }
+            Literal::Map(map) => map.borrow_mut().insert(index, value),
+            _ => Err(not_indexable()),
+        }
+    }
+
+    /// The name of the type of this value, as reported by the `type()` native.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Literal::Identifier(_) => "identifier",
+            Literal::Fun(_) | Literal::Native(_) => "function",
+            Literal::Class(_) => "class",
+            Literal::Instance(_) => "instance",
+            Literal::List(_) => "list",
+            Literal::Map(_) => "map",
+            Literal::String(_) => "string",
+            Literal::Number(_) => "number",
+            Literal::Nil => "nil",
+            Literal::Bool(_) => "bool",
+        }
+    }
+
+    pub(crate) fn callable(&self) -> Option<&dyn Callable> {
         match self {
-            Self::Fun(fun) => Some(*fun.clone()),
+            Self::Fun(LoxFunction(FunctionObject::TreeWalker(fun))) => Some(fun.as_ref()),
+            Self::Native(native) => Some(native.as_ref()),
+            Self::Class(LoxClass(ClassObject::TreeWalker(class))) => Some(class),
             _ => None,
         }
     }
@@ -171,10 +417,31 @@ impl Display for Literal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Literal::Identifier(i) => write!(f, "<{i}>"),
-            Literal::Fun(fun) => {
-                let name = fun.deref().name().lexeme();
-                write!(f, "<fn {name}>")
-            }
+            Literal::Fun(LoxFunction(fun)) => match fun {
+                FunctionObject::TreeWalker(fun) if fun.is_anonymous() => write!(f, "<fn>"),
+                FunctionObject::TreeWalker(fun) => {
+                    let name = fun.deref().name().lexeme();
+                    write!(f, "<fn {name}>")
+                }
+                FunctionObject::Closure(closure) => write!(f, "{}", closure.prototype()),
+                FunctionObject::BoundMethod(bound) => {
+                    write!(f, "{}", bound.method().prototype())
+                }
+            },
+            Literal::Native(native) => write!(f, "<native fn {}>", native.name()),
+            Literal::Class(LoxClass(class)) => match class {
+                ClassObject::TreeWalker(class) => write!(f, "{}", class.name().lexeme()),
+                ClassObject::Vm(class) => write!(f, "{}", class.name()),
+            },
+            Literal::Instance(LoxInstance(instance)) => match instance {
+                InstanceObject::TreeWalker(instance) => {
+                    write!(f, "{} instance", instance.borrow().class().name().lexeme())
+                }
+                InstanceObject::Vm(instance) => {
+                    write!(f, "{} instance", instance.borrow().class().name())
+                }
+            },
+            Literal::List(_) | Literal::Map(_) => write_collection(self, f, &mut Vec::new()),
             Literal::String(s) => write!(f, "{s}"),
             Literal::Number(n) => write!(f, "{n}"),
             Literal::Nil => write!(f, "nil"),
@@ -183,6 +450,156 @@ impl Display for Literal {
     }
 }
 
+/// Displays a value inside of a list or map. Strings are quoted, so that `["a, b"]` can be told
+/// apart from `["a", "b"]`.
+struct Element<'a>(&'a Literal);
+
+impl Display for Element<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_element(self.0, f, &mut Vec::new())
+    }
+}
+
+/// Write `element` the way [`Element`] displays it, inside of the collections in `enclosing`.
+fn write_element(
+    element: &Literal,
+    f: &mut std::fmt::Formatter<'_>,
+    enclosing: &mut Vec<*const ()>,
+) -> std::fmt::Result {
+    match element {
+        Literal::String(s) => write!(f, "{s:?}"),
+        Literal::List(_) | Literal::Map(_) => write_collection(element, f, enclosing),
+        element => write!(f, "{element}"),
+    }
+}
+
+/// Write a list or map, inside of the collections in `enclosing`. A collection that contains
+/// itself is shown as `[...]` or `{...}` where it comes up again, rather than forever.
+fn write_collection(
+    collection: &Literal,
+    f: &mut std::fmt::Formatter<'_>,
+    enclosing: &mut Vec<*const ()>,
+) -> std::fmt::Result {
+    let pointer = match collection {
+        Literal::List(list) => Rc::as_ptr(list).cast(),
+        Literal::Map(map) => Rc::as_ptr(map).cast(),
+        _ => unreachable!("only lists and maps are collections"),
+    };
+    if enclosing.contains(&pointer) {
+        return match collection {
+            Literal::List(_) => write!(f, "[...]"),
+            _ => write!(f, "{{...}}"),
+        };
+    }
+
+    enclosing.push(pointer);
+    match collection {
+        Literal::List(list) => {
+            write!(f, "[")?;
+            for (i, element) in list.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_element(element, f, enclosing)?;
+            }
+            write!(f, "]")?;
+        }
+        Literal::Map(map) => {
+            write!(f, "{{")?;
+            for (i, (key, value)) in map.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_element(key, f, enclosing)?;
+                write!(f, ": ")?;
+                write_element(value, f, enclosing)?;
+            }
+            write!(f, "}}")?;
+        }
+        _ => unreachable!("only lists and maps are collections"),
+    }
+    enclosing.pop();
+    Ok(())
+}
+
+impl From<f64> for Literal {
+    fn from(value: f64) -> Self {
+        Literal::Number(value)
+    }
+}
+
+impl From<bool> for Literal {
+    fn from(value: bool) -> Self {
+        Literal::Bool(value)
+    }
+}
+
+impl From<String> for Literal {
+    fn from(value: String) -> Self {
+        Literal::String(value)
+    }
+}
+
+impl From<&str> for Literal {
+    fn from(value: &str) -> Self {
+        Literal::String(value.to_string())
+    }
+}
+
+impl From<Vec<Literal>> for Literal {
+    /// Wrap `value` in a new list.
+    fn from(value: Vec<Literal>) -> Self {
+        Literal::List(Rc::new(RefCell::new(value)))
+    }
+}
+
+impl From<Map> for Literal {
+    fn from(value: Map) -> Self {
+        Literal::Map(Rc::new(RefCell::new(value)))
+    }
+}
+
+fn not_indexable() -> RuntimeError {
+    RuntimeError::new(
+        Code::NotIndexable,
+        "Only lists and maps can be indexed.".to_string(),
+    )
+}
+
+/// Build the error message for a failed conversion from a Literal into a Rust type.
+fn conversion_error(expected: &str, value: &Literal) -> String {
+    format!("Expected {expected} but got {}.", value.type_name())
+}
+
+impl TryFrom<Literal> for f64 {
+    type Error = String;
+
+    fn try_from(value: Literal) -> Result<Self, Self::Error> {
+        value
+            .number()
+            .ok_or_else(|| conversion_error("number", &value))
+    }
+}
+
+impl TryFrom<Literal> for bool {
+    type Error = String;
+
+    fn try_from(value: Literal) -> Result<Self, Self::Error> {
+        value.bool().ok_or_else(|| conversion_error("bool", &value))
+    }
+}
+
+impl TryFrom<Literal> for String {
+    type Error = String;
+
+    fn try_from(value: Literal) -> Result<Self, Self::Error> {
+        match value {
+            Literal::String(s) => Ok(s),
+            _ => Err(conversion_error("string", &value)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
     // Single-character tokens.
@@ -190,6 +607,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Dot,
     Comma,
     Minus,
@@ -215,7 +635,9 @@ pub enum TokenType {
 
     // Keywords.
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     Fun,
@@ -225,6 +647,7 @@ pub enum TokenType {
     Or,
     Print,
     Return,
+    Super,
     This,
     True,
     Var,
@@ -0,0 +1,686 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{stdout, Write};
+use std::rc::Rc;
+
+use crate::callable::Callable;
+use crate::chunk::{Constant, OpCode, Prototype};
+use crate::diagnostic::Code;
+use crate::environment::{Environment, EnvironmentRef};
+use crate::map::Map;
+use crate::native;
+use crate::token::{ClassObject, FunctionObject, Literal, LoxClass, LoxFunction};
+use crate::{Frame, LoxError};
+
+/// How deep calls may nest before the VM gives up with a stack overflow.
+pub(crate) const FRAMES_MAX: usize = 1024;
+
+/// A variable captured by a closure.
+///
+/// While the variable is still in scope, the upvalue is open and points at its slot on the
+/// stack. Once the variable goes out of scope, its value is moved into the upvalue itself.
+#[derive(Debug)]
+pub(crate) enum Upvalue {
+    Open(usize),
+    Closed(Literal),
+}
+
+/// A function prototype together with the variables it captured when it was created.
+#[derive(Debug)]
+pub struct Closure {
+    prototype: Rc<Prototype>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub(crate) fn prototype(&self) -> &Prototype {
+        &self.prototype
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    name: String,
+    methods: RefCell<HashMap<String, Rc<Closure>>>,
+}
+
+impl Class {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    class: Rc<Class>,
+    fields: HashMap<String, Literal>,
+}
+
+impl Instance {
+    pub(crate) fn class(&self) -> &Class {
+        &self.class
+    }
+}
+
+/// A method together with the instance it was accessed on.
+#[derive(Debug)]
+pub struct BoundMethod {
+    receiver: Literal,
+    method: Rc<Closure>,
+}
+
+impl BoundMethod {
+    pub(crate) fn method(&self) -> &Closure {
+        &self.method
+    }
+
+    pub(crate) fn is_same(&self, other: &BoundMethod) -> bool {
+        Rc::ptr_eq(&self.method, &other.method)
+            && Literal::is_equal(self.receiver.clone(), other.receiver.clone()).is_truthy()
+    }
+}
+
+#[derive(Debug)]
+struct CallFrame {
+    closure: Rc<Closure>,
+    /// The offset of the next instruction in the chunk of the closure.
+    ip: usize,
+    /// Where the slots of this call start on the stack.
+    base: usize,
+}
+
+/// A stack-based virtual machine that runs the bytecode produced by the
+/// [`Compiler`](crate::compiler::Compiler).
+///
+/// It is an alternative to the tree-walking [`Interpreter`](crate::interpreter::Interpreter),
+/// and runs the same programs with the same results.
+pub(crate) struct Vm {
+    globals: EnvironmentRef,
+    stack: Vec<Literal>,
+    frames: Vec<CallFrame>,
+    /// The upvalues that still point into the stack, ordered by their slot.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// Where `print` statements write to.
+    output: Box<dyn Write>,
+}
+
+impl Debug for Vm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vm")
+            .field("globals", &self.globals)
+            .field("stack", &self.stack)
+            .field("frames", &self.frames)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Vm {
+    pub(crate) fn new() -> Self {
+        let mut globals = Environment::new();
+        native::define_prelude(&mut globals);
+
+        Self {
+            globals: globals.shared(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            output: Box::new(stdout()),
+        }
+    }
+
+    pub(crate) fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub(crate) fn globals(&self) -> &EnvironmentRef {
+        &self.globals
+    }
+
+    /// Run a compiled script, and return what it returns.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a runtime error occurs. The stack is cleared
+    /// afterwards, but globals defined before the error remain.
+    pub(crate) fn interpret(&mut self, script: Rc<Prototype>) -> Result<Literal, LoxError> {
+        let closure = Rc::new(Closure {
+            prototype: script,
+            upvalues: Vec::new(),
+        });
+        self.stack.push(Literal::closure(Rc::clone(&closure)));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: 0,
+        });
+
+        let result = self.run().map_err(|error| error.with_trace(self.trace()));
+        if result.is_err() {
+            // Closures that escaped into the globals must not keep pointing into the stack.
+            self.close_upvalues(0);
+            self.stack.clear();
+            self.frames.clear();
+        }
+        result
+    }
+
+    /// The calls in progress, innermost first, each located at the instruction of its caller
+    /// that made it.
+    fn trace(&self) -> Vec<Frame> {
+        self.frames
+            .windows(2)
+            .rev()
+            .map(|frames| {
+                let [caller, callee] = frames else {
+                    unreachable!()
+                };
+                let (line, col) = caller.closure.prototype.chunk.position(caller.ip - 1);
+                Frame::new(&callee.closure.prototype.name, line, col)
+            })
+            .collect()
+    }
+
+    /// Run until the script returns, and return what it returns.
+    fn run(&mut self) -> Result<Literal, LoxError> {
+        loop {
+            let byte = self.read_byte();
+            let Some(op) = OpCode::from_byte(byte) else {
+                return Err(self.error(format!("Unknown opcode {byte}.")));
+            };
+
+            match op {
+                OpCode::Constant => {
+                    let value = match self.read_constant() {
+                        Constant::Number(n) => Literal::Number(n),
+                        Constant::String(s) => Literal::String(s.to_string()),
+                        Constant::Function(_) => {
+                            return Err(self.error("Expected a value constant.".to_string()))
+                        }
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::Nil => self.stack.push(Literal::Nil),
+                OpCode::True => self.stack.push(Literal::Bool(true)),
+                OpCode::False => self.stack.push(Literal::Bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_name()?;
+                    let value = self.globals.borrow().get(&name);
+                    match value {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            return Err(self
+                                .error_at(&name, format!("Undefined variable '{name}'."))
+                                .with_code(Code::UndefinedVariable))
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_name()?;
+                    let value = self.pop();
+                    self.globals.borrow_mut().define(name.to_string(), value);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_name()?;
+                    if self.globals.borrow().get(&name).is_none() {
+                        return Err(self
+                            .error_at(&name, format!("Undefined variable '{name}'."))
+                            .with_code(Code::UndefinedVariable));
+                    }
+                    let value = self.peek(0).clone();
+                    self.globals.borrow_mut().define(name.to_string(), value);
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let value = self.peek(0).clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_name()?;
+                    let Some(instance) = self.peek(0).as_vm_instance().cloned() else {
+                        return Err(self
+                            .error_at(&name, "Only instances have properties.".to_string())
+                            .with_code(Code::NotAnInstance));
+                    };
+
+                    let field = instance.borrow().fields.get(&*name).cloned();
+                    let value = match field {
+                        Some(value) => value,
+                        None => {
+                            let class = Rc::clone(&instance.borrow().class);
+                            self.bind_method(&class, &name, Literal::vm_instance(instance))?
+                        }
+                    };
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_name()?;
+                    let value = self.pop();
+                    let Some(instance) = self.pop().as_vm_instance().cloned() else {
+                        return Err(self
+                            .error_at(&name, "Only instances have fields.".to_string())
+                            .with_code(Code::NotAnInstance));
+                    };
+                    instance
+                        .borrow_mut()
+                        .fields
+                        .insert(name.to_string(), value.clone());
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_name()?;
+                    let Some(superclass) = self.pop().as_vm_class().cloned() else {
+                        return Err(self.error("Expected a superclass.".to_string()));
+                    };
+                    let receiver = self.pop();
+                    let method = self.bind_method(&superclass, &name, receiver)?;
+                    self.stack.push(method);
+                }
+                OpCode::BuildList => {
+                    let count = self.read_u16() as usize;
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(Literal::from(elements));
+                }
+                OpCode::BuildMap => {
+                    let count = self.read_u16() as usize;
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count);
+                    let mut map = Map::new();
+                    for entry in entries.chunks_exact(2) {
+                        map.insert(entry[0].clone(), entry[1].clone())
+                            .map_err(|error| {
+                                error.located(|message| self.error_at("{", message))
+                            })?;
+                    }
+                    self.stack.push(Literal::from(map));
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = object
+                        .get_index(&index)
+                        .map_err(|error| error.located(|message| self.error_at("[", message)))?;
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    object
+                        .set_index(index, value.clone())
+                        .map_err(|error| error.located(|message| self.error_at("[", message)))?;
+                    self.stack.push(value);
+                }
+                OpCode::Equal => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(Literal::is_equal(left, right));
+                }
+                OpCode::Greater => self.compare(f64::gt, bool::gt),
+                OpCode::GreaterEqual => self.compare(f64::ge, bool::ge),
+                OpCode::Less => self.compare(f64::lt, bool::lt),
+                OpCode::LessEqual => self.compare(f64::le, bool::le),
+                OpCode::Add => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let sum = match (left, right) {
+                        (Literal::Number(l), Literal::Number(r)) => Literal::Number(l + r),
+                        (Literal::String(l), Literal::String(r)) => Literal::String(l + &r),
+                        _ => return Err(self.unexpected_type(op)),
+                    };
+                    self.stack.push(sum);
+                }
+                OpCode::Subtract => self.arithmetic(op, |l, r| l - r)?,
+                OpCode::Multiply => self.arithmetic(op, |l, r| l * r)?,
+                OpCode::Divide => self.arithmetic(op, |l, r| l / r)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Literal::Bool(!value.is_truthy()));
+                }
+                OpCode::Negate => {
+                    let Literal::Number(n) = self.pop() else {
+                        return Err(self.unexpected_type(op));
+                    };
+                    self.stack.push(Literal::Number(-n));
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    writeln!(self.output, "{value}").map_err(|e| {
+                        LoxError::without_location(format!("Could not write output: {e}"))
+                    })?;
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16() as usize;
+                    if !self.peek(0).is_truthy() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let argument_count = self.read_byte() as usize;
+                    self.call_value(argument_count)?;
+                }
+                OpCode::Closure => {
+                    let Constant::Function(prototype) = self.read_constant() else {
+                        return Err(self.error("Expected a function constant.".to_string()));
+                    };
+                    let mut upvalues = Vec::with_capacity(prototype.upvalue_count);
+                    for _ in 0..prototype.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().base + index)
+                        } else {
+                            Rc::clone(&self.frame().closure.upvalues[index])
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    self.stack.push(Literal::closure(Rc::new(Closure {
+                        prototype,
+                        upvalues,
+                    })));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("a frame is running");
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.stack.push(result);
+                }
+                OpCode::Class => {
+                    let name = self.read_name()?;
+                    self.stack.push(Literal::vm_class(Rc::new(Class {
+                        name: name.to_string(),
+                        methods: RefCell::new(HashMap::new()),
+                    })));
+                }
+                OpCode::Inherit => {
+                    let superclass_name = self.read_name()?;
+                    let Some(subclass) = self.pop().as_vm_class().cloned() else {
+                        return Err(self.error("Expected a class.".to_string()));
+                    };
+                    let Some(superclass) = self.peek(0).as_vm_class() else {
+                        return Err(self
+                            .error_at(&superclass_name, "Superclass must be a class.".to_string())
+                            .with_code(Code::SuperclassNotClass));
+                    };
+                    // Methods can't be added to a class after its declaration, so copying them
+                    // down is as good as looking them up in the superclass later on.
+                    let methods = superclass.methods.borrow().clone();
+                    subclass.methods.borrow_mut().extend(methods);
+                }
+                OpCode::Method => {
+                    let name = self.read_name()?;
+                    let Some(method) = self.pop().as_closure().cloned() else {
+                        return Err(self.error("Expected a method.".to_string()));
+                    };
+                    let Some(class) = self.peek(0).as_vm_class() else {
+                        return Err(self.error("Expected a class.".to_string()));
+                    };
+                    class.methods.borrow_mut().insert(name.to_string(), method);
+                }
+            }
+        }
+    }
+
+    /// Call the value below the `argument_count` arguments on top of the stack.
+    fn call_value(&mut self, argument_count: usize) -> Result<(), LoxError> {
+        let callee_slot = self.stack.len() - argument_count - 1;
+        match self.stack[callee_slot].clone() {
+            Literal::Fun(LoxFunction(FunctionObject::Closure(closure))) => {
+                self.call(closure, argument_count)
+            }
+            Literal::Fun(LoxFunction(FunctionObject::BoundMethod(bound))) => {
+                self.stack[callee_slot] = bound.receiver.clone();
+                self.call(Rc::clone(&bound.method), argument_count)
+            }
+            Literal::Class(LoxClass(ClassObject::Vm(class))) => {
+                let instance = Instance {
+                    class: Rc::clone(&class),
+                    fields: HashMap::new(),
+                };
+                self.stack[callee_slot] = Literal::vm_instance(Rc::new(RefCell::new(instance)));
+
+                let initializer = class.methods.borrow().get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call(initializer, argument_count),
+                    None => {
+                        self.check_arity(0, argument_count)?;
+                        Ok(())
+                    }
+                }
+            }
+            Literal::Native(native) => {
+                self.check_arity(native.arity(), argument_count)?;
+                let arguments = self.stack.split_off(callee_slot + 1);
+                self.pop();
+                let result = native
+                    .invoke(arguments)
+                    .map_err(|error| error.located(|message| self.error_at(")", message)))?;
+                self.stack.push(result);
+                Ok(())
+            }
+            _ => Err(self
+                .error_at(")", "Can only call functions and classes.".to_string())
+                .with_code(Code::NotCallable)),
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, argument_count: usize) -> Result<(), LoxError> {
+        self.check_arity(closure.prototype.arity, argument_count)?;
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self
+                .error("Stack overflow.".to_string())
+                .with_code(Code::StackOverflow));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: self.stack.len() - argument_count - 1,
+        });
+        Ok(())
+    }
+
+    fn check_arity(&self, arity: usize, argument_count: usize) -> Result<(), LoxError> {
+        if arity != argument_count {
+            return Err(self
+                .error_at(
+                    ")",
+                    format!("Expected {arity} + arguments but got {argument_count}."),
+                )
+                .with_code(Code::WrongArity));
+        }
+        Ok(())
+    }
+
+    /// Look up the method `name` in `class`, and bind it to `receiver`.
+    fn bind_method(
+        &self,
+        class: &Class,
+        name: &str,
+        receiver: Literal,
+    ) -> Result<Literal, LoxError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => Ok(Literal::bound_method(Rc::new(BoundMethod {
+                receiver,
+                method,
+            }))),
+            None => Err(self
+                .error_at(name, format!("Undefined property '{name}'."))
+                .with_code(Code::UndefinedProperty)),
+        }
+    }
+
+    /// Get the upvalue for the stack slot `slot`, reusing it if a closure already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .partition_point(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s < slot));
+        if let Some(existing) = self.open_upvalues.get(position) {
+            if matches!(*existing.borrow(), Upvalue::Open(s) if s == slot) {
+                return Rc::clone(existing);
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(position, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Move the values of all upvalues pointing at `from` or above off the stack.
+    fn close_upvalues(&mut self, from: usize) {
+        let position = self
+            .open_upvalues
+            .partition_point(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s < from));
+        for upvalue in self.open_upvalues.drain(position..) {
+            let mut upvalue = upvalue.borrow_mut();
+            if let Upvalue::Open(slot) = *upvalue {
+                *upvalue = Upvalue::Closed(self.stack[slot].clone());
+            }
+        }
+    }
+
+    /// Compare the two values on top of the stack the way the tree-walking interpreter does:
+    /// numbers and booleans by value, anything else by truthiness.
+    fn compare(&mut self, numbers: fn(&f64, &f64) -> bool, bools: fn(&bool, &bool) -> bool) {
+        let right = self.pop();
+        let left = self.pop();
+        let result = match (left, right) {
+            (Literal::Number(l), Literal::Number(r)) => numbers(&l, &r),
+            (Literal::Bool(l), Literal::Bool(r)) => bools(&l, &r),
+            (l, r) => bools(&l.is_truthy(), &r.is_truthy()),
+        };
+        self.stack.push(Literal::Bool(result));
+    }
+
+    fn arithmetic(&mut self, op: OpCode, f: impl Fn(f64, f64) -> f64) -> Result<(), LoxError> {
+        let right = self.pop();
+        let left = self.pop();
+        let (Literal::Number(l), Literal::Number(r)) = (left, right) else {
+            return Err(self.unexpected_type(op));
+        };
+        self.stack.push(Literal::Number(f(l, r)));
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("a frame is running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("a frame is running")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.prototype.chunk.code()[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        u16::from_be_bytes([self.read_byte(), self.read_byte()])
+    }
+
+    fn read_constant(&mut self) -> Constant {
+        let index = self.read_u16() as usize;
+        self.frame().closure.prototype.chunk.constants()[index].clone()
+    }
+
+    fn read_name(&mut self) -> Result<Rc<str>, LoxError> {
+        match self.read_constant() {
+            Constant::String(name) => Ok(name),
+            _ => Err(self.error("Expected a name constant.".to_string())),
+        }
+    }
+
+    fn pop(&mut self) -> Literal {
+        self.stack
+            .pop()
+            .expect("the compiler keeps the stack balanced")
+    }
+
+    fn peek(&self, distance: usize) -> &Literal {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// The `(line, col)` of the instruction that is being executed.
+    fn position(&self) -> (usize, usize) {
+        let frame = self.frame();
+        frame
+            .closure
+            .prototype
+            .chunk
+            .position(frame.ip.saturating_sub(1))
+    }
+
+    fn error(&self, message: String) -> LoxError {
+        let (line, col) = self.position();
+        LoxError::new(line, col, message)
+    }
+
+    /// An error located at the instruction that is being executed, which was compiled from a
+    /// token with the lexeme `lexeme`.
+    fn error_at(&self, lexeme: &str, message: String) -> LoxError {
+        let (line, col) = self.position();
+        LoxError::with_place(line, col, format!("at '{lexeme}'"), message)
+            .with_end_col(col + lexeme.chars().count().saturating_sub(1))
+    }
+
+    /// The error the tree-walking interpreter raises for an operand of the wrong type.
+    fn unexpected_type(&self, op: OpCode) -> LoxError {
+        let (token_type, lexeme) = match op {
+            OpCode::Add => ("Plus", "+"),
+            OpCode::Subtract | OpCode::Negate => ("Minus", "-"),
+            OpCode::Multiply => ("Star", "*"),
+            OpCode::Divide => ("Slash", "/"),
+            _ => unreachable!(),
+        };
+        self.error_at(
+            lexeme,
+            format!("Unexpected type of token {token_type} {lexeme}"),
+        )
+        .with_code(Code::UnexpectedType)
+    }
+}